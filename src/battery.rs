@@ -1,11 +1,18 @@
 use gtk::{
     timeout_add,
+    ContainerExt,
     Continue,
     Label,
     LabelExt,
     WidgetExt
 };
+use gtk::Orientation::Horizontal;
+use dbus::BusType;
+use glib::SourceId;
 use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    fmt,
     fs,
     fs::File,
     io::{
@@ -13,26 +20,379 @@ use std::{
         BufReader,
         self
     },
-    iter::FromIterator,
-    rc::Rc
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::mpsc::{channel, Receiver},
+    thread,
+    time::Duration
 };
-use crate::paint::set_label_color;
+use crate::command::spawn_detached;
+use crate::format::format;
+use crate::notify::{notify, Urgency};
+use crate::paint::{set_label_color, CRITICAL_COLOR, DEFAULT_COLOR, WARNING_COLOR};
+use crate::upower::{self, UPowerDevices};
+use crate::Settings;
 
-const POWER_SUPPLY_ROOT: &str = "/sys/class/power_supply";
+/// Relative to the sysfs root.
+const POWER_SUPPLY_DIR: &str = "class/power_supply";
 const REFRESH_RATE: u32 = 1000;
+const ERROR_ICON: &str = " ";
+const DEFAULT_FORMAT: &str = "{icon}{percent}%";
+/// Rate samples averaged for the time estimates, one per refresh.
+const RATE_WINDOW: usize = 30;
+/// Points above a threshold the charge must go back to before it fires again.
+const ALERT_HYSTERESIS: u8 = 2;
+
+/// There is one bar per monitor, only the first battery widget notifies and
+/// runs the actions.
+static ALERTS_CLAIMED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// Reads `/sys/class/power_supply` every second.
+    Sysfs,
+    /// Listens to UPower, also showing peripherals like mice and headsets.
+    UPower
+}
+
+#[derive(Clone)]
+pub struct BatterySettings {
+    pub backend: Backend,
+    /// Talks to UPower on the session bus instead of the system one, e.g.
+    /// to a fake service.
+    pub upower_session: bool,
+    /// Shows one label per battery instead of their aggregate.
+    pub separate: bool,
+    /// Placeholders are `{icon}`, `{percent}`, `{state}`, `{time}` (to empty
+    /// or to full) and `{power}` (in watts).
+    pub format: String,
+    /// Charge percentages under which the battery turns yellow, then red
+    /// and blinking, while discharging.
+    pub warning: u8,
+    pub critical: u8,
+    /// Sends a desktop notification when crossing the two thresholds above.
+    pub notify: bool,
+    /// Shell commands run once when discharging down to a percentage.
+    pub actions: Vec<(u8, String)>
+}
+
+impl Default for BatterySettings {
+    fn default() -> BatterySettings {
+        BatterySettings {
+            backend: Backend::Sysfs,
+            upower_session: false,
+            separate: false,
+            format: String::from(DEFAULT_FORMAT),
+            warning: 20,
+            critical: 10,
+            notify: false,
+            actions: Vec::new()
+        }
+    }
+}
+
+enum AlertAction {
+    Notify(Urgency),
+    Command(String)
+}
+
+/// Reaction to the charge going down to `threshold`, fired once and armed
+/// again when the battery stops discharging or recharges above it.
+struct Alert {
+    threshold: u8,
+    action: AlertAction,
+    armed: bool
+}
+
+/// `POWER_SUPPLY_STATUS`, with `Unknown` resolved through the AC adapter
+/// when there is one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Full,
+    Charging,
+    Discharging,
+    /// Plugged but held below full, e.g. by charge thresholds.
+    NotCharging,
+    /// Plugged and reported as `Unknown` by the battery.
+    Plugged,
+    Unknown
+}
+
+#[derive(Clone)]
+pub struct Battery {
+    pub name: String,
+    pub state: State,
+    pub charge: u8,
+    /// In µWh, or µAh for batteries only reporting charge.
+    pub energy_now: Option<u64>,
+    pub energy_full: Option<u64>,
+    /// Charge or discharge rate, in the unit of `energy_now` per hour.
+    pub rate: Option<u64>,
+    pub watts: Option<f64>,
+    /// Time to empty or full computed by the backend, preferred over ours.
+    pub estimate: Option<Duration>,
+    /// Replaces the charge level icon, for peripherals.
+    pub device_icon: Option<&'static str>
+}
+
+/// Last rates of a battery while in the same state, so the estimates do not
+/// jump around with every load change.
+struct RateWindow {
+    state: State,
+    samples: VecDeque<u64>
+}
+
+#[derive(Debug)]
+enum BatteryError {
+    Io(io::Error),
+    Parse(String, String),
+    MissingKey(&'static str)
+}
 
 pub struct BatteryWidget {
-    charge: u8,
-    name: String,
-    state: String,
+    id: usize,
+    container: gtk::Box,
+    labels: Vec<Label>,
+    power_supply: PathBuf,
+    names: Vec<String>,
+    rx: Option<Receiver<UPowerDevices>>,
+    /// Last UPower state, rendered again for blinking.
+    devices: Option<UPowerDevices>,
+    separate: bool,
+    format: String,
+    rate_windows: HashMap<String, RateWindow>,
+    warning: u8,
+    critical: u8,
+    alerts: Vec<Alert>,
+    blink: bool,
+    blink_timer: Option<SourceId>,
+    blinking: bool,
+    debug: bool,
+    texts: Vec<String>,
+    colors: Vec<(u8, u8, u8)>,
+    tooltip: String
+}
+
+thread_local!(
+    static BATTERY_WIDGETS: RefCell<Vec<BatteryWidget>> = RefCell::new(Vec::new())
+);
+
+impl fmt::Display for BatteryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatteryError::Io(e) => write!(f, "{}", e),
+            BatteryError::Parse(key, value) => write!(f, "Invalid {} value \"{}\"", key, value),
+            BatteryError::MissingKey(key) => write!(f, "Missing {}", key)
+        }
+    }
+}
+
+impl From<io::Error> for BatteryError {
+    fn from(e: io::Error) -> BatteryError {
+        BatteryError::Io(e)
+    }
+}
+
+impl State {
+    fn from_status(status: &str, plugged: Option<bool>) -> State {
+        match (status, plugged) {
+            ("Full", _) => State::Full,
+            ("Charging", _) => State::Charging,
+            ("Discharging", _) => State::Discharging,
+            ("Not charging", _) => State::NotCharging,
+            (_, Some(false)) => State::Discharging,
+            (_, Some(true)) => State::Plugged,
+            // Without any adapter to ask, nothing tells where the power comes from
+            (_, None) => State::Unknown
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", match self {
+            State::Full => "Full",
+            State::Charging => "Charging",
+            State::Discharging => "Discharging",
+            State::NotCharging => "Not charging",
+            State::Plugged => "Plugged",
+            State::Unknown => "Unknown"
+        })
+    }
+}
+
+impl Alert {
+    /// Returns true when the alert should fire now.
+    fn check(&mut self, battery: &Battery) -> bool {
+        if battery.state != State::Discharging
+            || battery.charge >= self.threshold.saturating_add(ALERT_HYSTERESIS)
+        {
+            self.armed = true;
+            return false;
+        }
+        if self.armed && battery.charge <= self.threshold {
+            self.armed = false;
+            return true;
+        }
+        false
+    }
+
+    fn fire(&self, battery: &Battery, debug: bool) {
+        match &self.action {
+            AlertAction::Notify(urgency) => notify(
+                &format!("Battery at {}%", battery.charge),
+                "Plug the charger in",
+                *urgency,
+                debug
+            ),
+            AlertAction::Command(command) => {
+                if let Err(e) = spawn_detached(Command::new("sh").arg("-c").arg(command)) {
+                    if debug { println!("{}", e); }
+                }
+            }
+        }
+    }
+}
+
+impl RateWindow {
+    /// Adds a sample and returns the average rate, the window being reset
+    /// whenever the battery switches between charging and discharging.
+    fn push(&mut self, state: State, rate: Option<u64>) -> Option<f64> {
+        if self.state != state {
+            self.state = state;
+            self.samples.clear();
+        }
+        if let Some(rate) = rate.filter(|rate| *rate > 0) {
+            if self.samples.len() == RATE_WINDOW {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(rate);
+        }
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<u64>() as f64 / self.samples.len() as f64)
+    }
+}
+
+fn format_hours(hours: f64) -> String {
+    let minutes = (hours * 60.0).round() as u64;
+    format!("{}:{:02}", minutes / 60, minutes % 60)
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, BatteryError> {
+    value.trim().parse::<T>().map_err(|_| BatteryError::Parse(key.to_string(), value.to_string()))
+}
+
+impl Battery {
+    /// Merges all batteries into one, weighting each charge by the energy
+    /// the battery holds when full.
+    fn aggregate(batteries: &[&Battery]) -> Battery {
+        let energy_now: Option<u64> = batteries.iter().map(|battery| battery.energy_now).sum();
+        let energy_full: Option<u64> = batteries.iter().map(|battery| battery.energy_full).sum();
+        let rate: Option<u64> = batteries.iter().map(|battery| battery.rate).sum();
+        let watts: Option<f64> = batteries.iter().map(|battery| battery.watts).sum();
+        let charge = match (energy_now, energy_full) {
+            (Some(now), Some(full)) if full > 0 => (now * 100 / full).min(100) as u8,
+            _ => {
+                let sum: u32 = batteries.iter().map(|battery| u32::from(battery.charge)).sum();
+                (sum / batteries.len().max(1) as u32) as u8
+            }
+        };
+        let has_state = |state| batteries.iter().any(|battery| battery.state == state);
+        let state = if has_state(State::Charging) {
+            State::Charging
+        } else if has_state(State::Discharging) {
+            State::Discharging
+        } else if batteries.iter().all(|battery| battery.state == State::Full) {
+            State::Full
+        } else if has_state(State::NotCharging) {
+            State::NotCharging
+        } else if has_state(State::Plugged) {
+            State::Plugged
+        } else {
+            State::Unknown
+        };
+        Battery {
+            name: String::from("Total"),
+            state,
+            charge,
+            energy_now,
+            energy_full,
+            rate,
+            watts,
+            estimate: None,
+            device_icon: None
+        }
+    }
+
+    /// Time until empty when discharging or until full when charging, from
+    /// the smoothed `rate`.
+    fn remaining(&self, rate: Option<f64>) -> Option<Duration> {
+        let rate = rate.filter(|rate| *rate > 0.0)?;
+        let hours = match (self.state, self.energy_now, self.energy_full) {
+            (State::Discharging, Some(now), _) => now as f64 / rate,
+            (State::Charging, Some(now), Some(full)) => full.saturating_sub(now) as f64 / rate,
+            _ => return None
+        };
+        Some(Duration::from_secs((hours * 3600.0) as u64))
+    }
+
+    fn icon(&self) -> &'static str {
+        if let Some(device_icon) = self.device_icon {
+            return device_icon;
+        }
+        match self.state {
+            State::Charging => " ",
+            State::Full => " ",
+            State::NotCharging => " ",
+            State::Plugged => " ",
+            State::Discharging | State::Unknown => match self.charge {
+                c if c < 20 => " ",
+                c if c < 40 => " ",
+                c if c < 60 => " ",
+                c if c < 80 => " ",
+                _ => " "
+            }
+        }
+    }
+
+    fn text(&self, template: &str, remaining: Option<Duration>) -> String {
+        format(template, |key| match key {
+            "icon" => Some(self.icon().to_string()),
+            "percent" => Some(self.charge.to_string()),
+            "state" => Some(self.state.to_string()),
+            "time" => remaining.map(|remaining| format_hours(remaining.as_secs() as f64 / 3600.0)),
+            "power" => self.watts.map(|watts| format!("{:.1}", watts)),
+            _ => None
+        })
+    }
+
+    fn tooltip(&self, remaining: Option<Duration>) -> String {
+        let mut tooltip = format!("{}: {}% {}", self.name, self.charge, self.state);
+        if let Some(watts) = self.watts.filter(|watts| *watts > 0.0) {
+            tooltip.push_str(&format!(", {:.1} W", watts));
+        }
+        if let Some(remaining) = remaining {
+            let hours = format_hours(remaining.as_secs() as f64 / 3600.0);
+            match self.state {
+                State::Charging => tooltip.push_str(&format!(", {} until full", hours)),
+                _ => tooltip.push_str(&format!(", {} left", hours))
+            }
+        }
+        tooltip
+    }
 }
 
 impl BatteryWidget {
-    fn get_battery_names() -> Vec<String> {
-        let dir_entries = fs::read_dir(POWER_SUPPLY_ROOT).unwrap();
+    /// Containers and desktops may have no power supply class at all, this
+    /// is the same as having no battery.
+    fn get_battery_names(power_supply: &Path) -> Result<Vec<String>, io::Error> {
         let mut batteries = Vec::new();
-        for dir_entry in dir_entries {
-            let path = dir_entry.unwrap().path();
+        for dir_entry in fs::read_dir(power_supply)? {
+            let path = dir_entry?.path();
             if let Some(file_stem) = path.file_stem() {
                 let file_stem_string = file_stem.to_string_lossy();
                 if file_stem_string.starts_with("BAT") {
@@ -40,75 +400,466 @@ impl BatteryWidget {
                 }
             }
         }
-        batteries
+        batteries.sort();
+        Ok(batteries)
+    }
+
+    /// Whether any `Mains` supply (`AC`, `ADP1`, ...) is online, `None` when
+    /// the machine exposes none.
+    fn get_plugged(power_supply: &Path) -> Option<bool> {
+        let mut plugged = None;
+        for dir_entry in fs::read_dir(power_supply).ok()? {
+            let path = match dir_entry {
+                Ok(dir_entry) => dir_entry.path(),
+                Err(_) => continue
+            };
+            match fs::read_to_string(path.join("type")) {
+                Ok(ref supply_type) if supply_type.trim() == "Mains" => {},
+                _ => continue
+            }
+            let online = fs::read_to_string(path.join("online"))
+                .map(|online| online.trim() == "1")
+                .unwrap_or(false);
+            plugged = Some(plugged.unwrap_or(false) || online);
+        }
+        plugged
     }
 
-    fn get_data(battery_name: &String) -> Result<(String, u8), io::Error> {
-        let file = File::open(format!("{}/{}/uevent", POWER_SUPPLY_ROOT, battery_name))?;
+    fn get_data(power_supply: &Path, battery_name: &str, plugged: Option<bool>) -> Result<Battery, BatteryError> {
+        let file = File::open(power_supply.join(battery_name).join("uevent"))?;
         let content = BufReader::new(&file);
-        let mut state = String::from("");
-        let mut charge = 0 as u8;
+        let mut status = None;
+        let mut battery = Battery {
+            name: battery_name.to_string(),
+            state: State::Unknown,
+            charge: 0,
+            energy_now: None,
+            energy_full: None,
+            rate: None,
+            watts: None,
+            estimate: None,
+            device_icon: None
+        };
+        let mut charge = None;
+        let (mut charge_now, mut charge_full) = (None, None);
+        let (mut power_now, mut current_now, mut voltage_now) = (None, None, None);
         for line in content.lines() {
             let line = line?;
-            let tokens = Vec::from_iter(line.split('=')); 
-            let token = tokens.last().unwrap();
-            match tokens.first().unwrap() {
-                &"POWER_SUPPLY_STATUS" => { state = token.to_string(); },
-                &"POWER_SUPPLY_CAPACITY" => { charge = token.parse::<u8>().unwrap(); },
+            let mut tokens = line.splitn(2, '=');
+            let (key, token) = match (tokens.next(), tokens.next()) {
+                (Some(key), Some(token)) => (key, token),
+                _ => continue
+            };
+            match key {
+                "POWER_SUPPLY_STATUS" => { status = Some(token.to_string()); },
+                "POWER_SUPPLY_CAPACITY" => { charge = Some(parse_value::<u8>(key, token)?.min(100)); },
+                "POWER_SUPPLY_ENERGY_NOW" => { battery.energy_now = Some(parse_value(key, token)?); },
+                "POWER_SUPPLY_ENERGY_FULL" => { battery.energy_full = Some(parse_value(key, token)?); },
+                "POWER_SUPPLY_CHARGE_NOW" => { charge_now = Some(parse_value(key, token)?); },
+                "POWER_SUPPLY_CHARGE_FULL" => { charge_full = Some(parse_value(key, token)?); },
+                // Some drivers report a negative rate while discharging
+                "POWER_SUPPLY_POWER_NOW" => { power_now = Some(parse_value::<i64>(key, token)?.abs() as u64); },
+                "POWER_SUPPLY_CURRENT_NOW" => { current_now = Some(parse_value::<i64>(key, token)?.abs() as u64); },
+                "POWER_SUPPLY_VOLTAGE_NOW" => { voltage_now = Some(parse_value::<u64>(key, token)?); },
                 _ => {}
             }
         }
-        Ok((state, charge))
-    }
-
-    fn update(&mut self, label: &Label, force_refresh: bool) {
-        match BatteryWidget::get_data(&self.name) {
-            Ok((state, charge)) => {
-                if self.charge != charge
-                    || self.state != state
-                        || force_refresh {
-                            let mut battery_icon = match charge {
-                                c if c < 20 => " ",
-                                c if c < 40 => " ",
-                                c if c < 60 => " ",
-                                c if c < 80 => " ",
-                                _ => " "
-                            };
-                            if charge != 100 {
-                                battery_icon = match state.as_ref() {
-                                    "Charging" => " ",
-                                    c if c == "Unknown" && charge != 100 => " ", // FIXME Find a way to get the real state 
-                                    _ => battery_icon
-                                };
-                            }
-                            let charge_string = &charge.to_string();
-                            label.set_text(&format!("{}{}%", battery_icon, charge_string));
-                            self.charge = charge;
-                            self.state = state;
-                        }
-            },
-            Err(_e) => {}
+        // Batteries reporting charge (µAh) instead of energy (µWh) are still
+        // weighted consistently as long as all of them do.
+        if battery.energy_now.is_none() && battery.energy_full.is_none() {
+            battery.energy_now = charge_now;
+            battery.energy_full = charge_full;
+            battery.rate = current_now;
+            battery.watts = current_now.and_then(|current| {
+                voltage_now.map(|voltage| current as f64 * voltage as f64 / 1e12)
+            });
+        } else {
+            battery.rate = power_now.or_else(|| {
+                current_now.and_then(|current| voltage_now.map(|voltage| current * voltage / 1_000_000))
+            });
+            battery.watts = battery.rate.map(|rate| rate as f64 / 1e6);
         }
+        battery.charge = match (charge, battery.energy_now, battery.energy_full) {
+            (Some(charge), _, _) => charge,
+            (None, Some(now), Some(full)) if full > 0 => (now * 100 / full).min(100) as u8,
+            _ => return Err(BatteryError::MissingKey("POWER_SUPPLY_CAPACITY"))
+        };
+        match status {
+            Some(status) => battery.state = State::from_status(&status, plugged),
+            None => return Err(BatteryError::MissingKey("POWER_SUPPLY_STATUS"))
+        }
+        Ok(battery)
     }
 
-    pub fn new() -> Label {
-        let label = Label::new(None);
-        label.set_margin_end(7);
-        set_label_color(&label, 255, 255, 255);
-        let label_rc = Rc::new(label.clone());
-        let name = &BatteryWidget::get_battery_names()[0];
-        let label_clone = label_rc.clone();
-        let (state, charge) = BatteryWidget::get_data(&name).unwrap_or_else(|_| (String::from(""), 0));
-        let mut battery = BatteryWidget {
-            charge,
-            name: name.to_string(),
-            state
+    fn update(&mut self, force_refresh: bool) {
+        let plugged = BatteryWidget::get_plugged(&self.power_supply);
+        let results: Vec<(String, Result<Battery, BatteryError>)> = self.names.iter()
+            .map(|name| (name.clone(), BatteryWidget::get_data(&self.power_supply, name, plugged)))
+            .collect();
+        let batteries: Vec<&Battery> = results.iter()
+            .filter_map(|(_, result)| result.as_ref().ok())
+            .collect();
+        let total = if batteries.is_empty() { None } else { Some(Battery::aggregate(&batteries)) };
+        self.render(&results, total, &[], force_refresh);
+    }
+
+    /// UPower already aggregates the laptop batteries in its display device.
+    fn update_devices(&mut self, force_refresh: bool) {
+        let devices = match self.devices.take() {
+            Some(devices) => devices,
+            None => return
         };
-        BatteryWidget::update(&mut battery, &label_clone, true); // TODO refactor to closure ?
-        timeout_add(REFRESH_RATE, move || {
-            BatteryWidget::update(&mut battery, &label_clone, false);
-            Continue(true)
+        let results: Vec<(String, Result<Battery, BatteryError>)> = devices.batteries.iter()
+            .map(|battery| (battery.name.clone(), Ok(battery.clone())))
+            .collect();
+        let total = devices.display.clone().or_else(|| {
+            let batteries: Vec<&Battery> = devices.batteries.iter().collect();
+            if batteries.is_empty() { None } else { Some(Battery::aggregate(&batteries)) }
         });
-        label
+        self.render(&results, total, &devices.peripherals, force_refresh);
+        self.devices = Some(devices);
+        self.set_blink_timer();
+    }
+
+    fn render(&mut self,
+              results: &[(String, Result<Battery, BatteryError>)],
+              total: Option<Battery>,
+              peripherals: &[Battery],
+              force_refresh: bool) {
+        let mut remainings = HashMap::new();
+        for battery in results.iter().filter_map(|(_, result)| result.as_ref().ok()) {
+            remainings.insert(battery.name.clone(), self.get_remaining(battery));
+        }
+        if let Some(total) = &total {
+            for alert in &mut self.alerts {
+                if alert.check(total) {
+                    alert.fire(total, self.debug);
+                }
+            }
+        }
+        self.blink = !self.blink;
+        let error_text = ERROR_ICON.to_string();
+        let (mut texts, mut colors): (Vec<String>, Vec<(u8, u8, u8)>) = if self.separate {
+            results.iter().map(|(_, result)| match result {
+                Ok(battery) => (battery.text(&self.format, remainings.get(&battery.name).cloned().flatten()), self.get_color(battery)),
+                Err(_) => (error_text.clone(), DEFAULT_COLOR)
+            }).unzip()
+        } else if let Some(total) = &total {
+            let remaining = self.get_remaining(total);
+            (vec![total.text(&self.format, remaining)], vec![self.get_color(total)])
+        } else if !results.is_empty() {
+            (vec![error_text], vec![DEFAULT_COLOR])
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        self.blinking = total.as_ref().map_or(false, |total| self.is_critical(total))
+            || peripherals.iter().any(|peripheral| self.is_critical(peripheral))
+            || (self.separate && results.iter().any(|(_, result)| match result {
+                Ok(battery) => self.is_critical(battery),
+                Err(_) => false
+            }));
+        for peripheral in peripherals {
+            texts.push(peripheral.text(&self.format, None));
+            colors.push(self.get_color(peripheral));
+        }
+        let force_refresh = self.set_label_count(texts.len()) || force_refresh;
+        for (i, (label, text)) in self.labels.iter().zip(texts.iter()).enumerate() {
+            if self.texts.get(i) != Some(text) || force_refresh {
+                label.set_text(text);
+            }
+        }
+        for (i, (label, color)) in self.labels.iter().zip(colors.iter()).enumerate() {
+            if self.colors.get(i) != Some(color) || force_refresh {
+                let (r, g, b) = *color;
+                set_label_color(label, r, g, b);
+            }
+        }
+        self.texts = texts;
+        self.colors = colors;
+        let tooltip = results.iter()
+            .map(|(name, result)| match result {
+                Ok(battery) => battery.tooltip(remainings.get(&battery.name).cloned().flatten()),
+                Err(e) => format!("{}: {}", name, e)
+            })
+            .chain(peripherals.iter().map(|peripheral| peripheral.tooltip(None)))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if self.tooltip != tooltip || force_refresh {
+            self.container.set_tooltip_text(Some(tooltip.as_str()));
+            self.tooltip = tooltip;
+        }
+    }
+
+    /// Adds or removes labels, peripherals coming and going with UPower.
+    /// Returns true when the labels changed.
+    fn set_label_count(&mut self, count: usize) -> bool {
+        if self.labels.len() == count {
+            return false;
+        }
+        while self.labels.len() > count {
+            if let Some(label) = self.labels.pop() {
+                self.container.remove(&label);
+            }
+        }
+        while self.labels.len() < count {
+            let label = Label::new(None);
+            label.set_margin_end(7);
+            self.container.add(&label);
+            label.show();
+            self.labels.push(label);
+        }
+        if count == 0 {
+            self.container.hide();
+        } else {
+            self.container.show();
+        }
+        true
+    }
+
+    fn is_critical(&self, battery: &Battery) -> bool {
+        battery.state == State::Discharging && battery.charge <= self.critical
+    }
+
+    /// UPower only signals changes, blinking needs its own timer while a
+    /// battery is critical.
+    fn set_blink_timer(&mut self) {
+        let id = self.id;
+        if self.blinking && self.blink_timer.is_none() {
+            self.blink_timer = Some(timeout_add(REFRESH_RATE, move || BatteryWidget::tick(id)));
+        } else if !self.blinking {
+            if let Some(blink_timer) = self.blink_timer.take() {
+                glib::source_remove(blink_timer);
+            }
+        }
+    }
+
+    pub fn receive(id: usize) {
+        BATTERY_WIDGETS.with(|battery_widgets| {
+            if let Some(battery_widget) = battery_widgets.borrow_mut().get_mut(id) {
+                let latest = battery_widget.rx.as_ref().and_then(|rx| rx.try_iter().last());
+                if latest.is_some() {
+                    battery_widget.devices = latest;
+                    battery_widget.update_devices(false);
+                }
+            }
+        });
+    }
+
+    fn tick(id: usize) -> Continue {
+        BATTERY_WIDGETS.with(|battery_widgets| {
+            if let Some(battery_widget) = battery_widgets.borrow_mut().get_mut(id) {
+                battery_widget.update_devices(false);
+            }
+        });
+        Continue(true)
+    }
+
+    /// Critical batteries blink by alternating with the default color on
+    /// every refresh.
+    fn get_color(&self, battery: &Battery) -> (u8, u8, u8) {
+        if battery.state != State::Discharging {
+            return DEFAULT_COLOR;
+        }
+        match battery.charge {
+            c if c <= self.critical && self.blink => DEFAULT_COLOR,
+            c if c <= self.critical => CRITICAL_COLOR,
+            c if c <= self.warning => WARNING_COLOR,
+            _ => DEFAULT_COLOR
+        }
+    }
+
+    fn get_remaining(&mut self, battery: &Battery) -> Option<Duration> {
+        if battery.estimate.is_some() {
+            return battery.estimate;
+        }
+        let rate = self.rate_windows.entry(battery.name.clone())
+            .or_insert_with(|| RateWindow { state: battery.state, samples: VecDeque::new() })
+            .push(battery.state, battery.rate);
+        battery.remaining(rate)
+    }
+
+    /// Only the first widget gets the alerts, there is one bar per monitor.
+    fn get_alerts(settings: &Settings) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        if ALERTS_CLAIMED.swap(true, Ordering::SeqCst) {
+            return alerts;
+        }
+        if settings.battery.notify {
+            alerts.push(Alert {
+                threshold: settings.battery.warning,
+                action: AlertAction::Notify(Urgency::Normal),
+                armed: true
+            });
+            alerts.push(Alert {
+                threshold: settings.battery.critical,
+                action: AlertAction::Notify(Urgency::Critical),
+                armed: true
+            });
+        }
+        for (threshold, command) in &settings.battery.actions {
+            alerts.push(Alert {
+                threshold: *threshold,
+                action: AlertAction::Command(command.clone()),
+                armed: true
+            });
+        }
+        alerts
+    }
+
+    pub fn new(settings: &Settings) -> gtk::Box {
+        let container = gtk::Box::new(Horizontal, 0);
+        // Shown by the first update with something to display
+        container.set_no_show_all(true);
+        let power_supply = settings.sysfs_root.join(POWER_SUPPLY_DIR);
+        let names = match settings.battery.backend {
+            Backend::Sysfs => {
+                let names = BatteryWidget::get_battery_names(&power_supply).unwrap_or_else(|e| {
+                    if settings.debug { println!("{}", e); }
+                    Vec::new()
+                });
+                if names.is_empty() {
+                    return container;
+                }
+                names
+            },
+            Backend::UPower => Vec::new()
+        };
+        let mut battery = BatteryWidget {
+            id: 0,
+            container: container.clone(),
+            labels: Vec::new(),
+            power_supply,
+            names,
+            rx: None,
+            devices: None,
+            separate: settings.battery.separate,
+            format: settings.battery.format.clone(),
+            rate_windows: HashMap::new(),
+            warning: settings.battery.warning,
+            critical: settings.battery.critical,
+            alerts: BatteryWidget::get_alerts(settings),
+            blink: false,
+            blink_timer: None,
+            blinking: false,
+            debug: settings.debug,
+            texts: Vec::new(),
+            colors: Vec::new(),
+            tooltip: String::new()
+        };
+        match settings.battery.backend {
+            Backend::Sysfs => {
+                battery.update(true);
+                timeout_add(REFRESH_RATE, move || {
+                    battery.update(false);
+                    Continue(true)
+                });
+            },
+            Backend::UPower => {
+                let (tx, rx) = channel();
+                let id = BATTERY_WIDGETS.with(|battery_widgets| battery_widgets.borrow().len());
+                battery.id = id;
+                battery.rx = Some(rx);
+                BATTERY_WIDGETS.with(|battery_widgets| battery_widgets.borrow_mut().push(battery));
+                let bus = if settings.battery.upower_session { BusType::Session } else { BusType::System };
+                let debug = settings.debug;
+                thread::spawn(move || {
+                    if let Err(e) = upower::watch(bus, id, tx) {
+                        if debug { println!("{}", e); }
+                    }
+                });
+            }
+        }
+        container
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    fn power_supply(machine: &str) -> PathBuf {
+        fixtures::sysfs(machine).join(POWER_SUPPLY_DIR)
+    }
+
+    fn load(machine: &str) -> Vec<Result<Battery, BatteryError>> {
+        let power_supply = power_supply(machine);
+        let plugged = BatteryWidget::get_plugged(&power_supply);
+        BatteryWidget::get_battery_names(&power_supply).unwrap().iter()
+            .map(|name| BatteryWidget::get_data(&power_supply, name, plugged))
+            .collect()
+    }
+
+    fn time_left(battery: &Battery) -> Option<String> {
+        battery.remaining(battery.rate.map(|rate| rate as f64))
+            .map(|remaining| format_hours(remaining.as_secs() as f64 / 3600.0))
+    }
+
+    #[test]
+    fn thinkpad_t480() {
+        let power_supply = power_supply("thinkpad-t480");
+        assert_eq!(BatteryWidget::get_battery_names(&power_supply).unwrap(), vec!["BAT0", "BAT1"]);
+        assert_eq!(BatteryWidget::get_plugged(&power_supply), Some(true));
+        let batteries: Vec<Battery> = load("thinkpad-t480").into_iter().map(Result::unwrap).collect();
+        assert_eq!(batteries[0].charge, 65);
+        assert_eq!(batteries[0].state, State::Charging);
+        assert_eq!(batteries[1].charge, 80);
+        assert_eq!(batteries[1].state, State::Plugged);
+        assert_eq!(time_left(&batteries[1]), None);
+        let total = Battery::aggregate(&batteries.iter().collect::<Vec<_>>());
+        assert_eq!(total.charge, 72);
+        assert_eq!(total.state, State::Charging);
+        assert_eq!(time_left(&total), Some(String::from("0:38")));
+    }
+
+    #[test]
+    fn dell_xps_13_reports_charge() {
+        let power_supply = power_supply("dell-xps-13");
+        assert_eq!(BatteryWidget::get_plugged(&power_supply), Some(false));
+        let battery = load("dell-xps-13").remove(0).unwrap();
+        assert_eq!(battery.charge, 16);
+        assert_eq!(battery.state, State::Discharging);
+        assert_eq!(battery.energy_now, Some(1_038_000));
+        assert_eq!(battery.energy_full, Some(6_110_000));
+        assert_eq!(time_left(&battery), Some(String::from("1:17")));
+    }
+
+    #[test]
+    fn pinebook_pro_without_capacity() {
+        assert_eq!(BatteryWidget::get_plugged(&power_supply("pinebook-pro")), None);
+        let battery = load("pinebook-pro").remove(0).unwrap();
+        assert_eq!(battery.charge, 7);
+        assert_eq!(battery.state, State::Discharging);
+        assert_eq!(battery.rate, Some(1_124_000));
+        assert_eq!(time_left(&battery), Some(String::from("0:39")));
+    }
+
+    #[test]
+    fn surface_go_unknown_without_adapter() {
+        assert_eq!(BatteryWidget::get_plugged(&power_supply("surface-go")), None);
+        let batteries: Vec<Battery> = load("surface-go").into_iter().map(Result::unwrap).collect();
+        assert_eq!(batteries[0].name, "BAT1");
+        let total = Battery::aggregate(&batteries.iter().collect::<Vec<_>>());
+        assert_eq!(total.charge, 100);
+        assert_eq!(total.state, State::Unknown);
+        assert_eq!(time_left(&total), None);
+    }
+
+    #[test]
+    fn broken_capacity() {
+        match load("broken-capacity").remove(0) {
+            Err(e @ BatteryError::Parse(..)) => {
+                assert_eq!(e.to_string(), "Invalid POWER_SUPPLY_CAPACITY value \"-ENODATA\"");
+            },
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("parsed a broken capacity")
+        }
+    }
+
+    #[test]
+    fn desktop_without_power_supply() {
+        let power_supply = power_supply("desktop");
+        assert_eq!(BatteryWidget::get_battery_names(&power_supply).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(BatteryWidget::get_plugged(&power_supply), None);
     }
 }
@@ -1,49 +1,370 @@
-use gtk::{Label, LabelExt};
+use gtk::{
+    timeout_add,
+    Calendar,
+    CalendarDisplayOptions,
+    CalendarExt,
+    ContainerExt,
+    EventBox,
+    Inhibit,
+    Label,
+    LabelExt,
+    Popover,
+    PopoverExt,
+    PositionType,
+    WidgetExt
+};
+use gtk::Orientation::{Horizontal, Vertical};
+use gio::{FileExt, FileMonitor, FileMonitorExt, FileMonitorFlags};
+use glib::{Continue, SourceId};
+use std::cell::RefCell;
+use std::iter;
+use std::path::PathBuf;
 use std::rc::Rc;
 
-use chrono::Local;
+use chrono::{Datelike, DateTime, Duration, Local, NaiveDate, Timelike, Utc};
+use chrono::format::{Item, StrftimeItems};
+use chrono_tz::Tz;
+use crate::events::{get_calendar_dirs, get_day_occurrences, get_next_event_text, load_events, Event};
+use crate::format::truncate;
 use crate::paint::set_label_color;
+use crate::Settings;
+
+const DEFAULT_FORMAT: &str = "%H:%M";
+/// How far ahead the next event shows up in the bar.
+const EVENT_HORIZON_HOURS: i64 = 12;
+const EVENT_WIDTH: usize = 30;
+
+#[derive(Clone)]
+pub struct ClockSettings {
+    /// strftime-like, e.g. `%a %d %H:%M`.
+    pub format: String,
+    /// Shown instead of `format` after a right click.
+    pub alt_format: Option<String>,
+    /// ISO week numbers in the calendar popover.
+    pub week_numbers: bool,
+    /// Extra clocks, next to the local one.
+    pub timezones: Vec<Tz>,
+    /// `.ics` files or vdir directories, for the next event.
+    pub calendars: Vec<PathBuf>
+}
+
+impl Default for ClockSettings {
+    fn default() -> ClockSettings {
+        ClockSettings {
+            format: String::from(DEFAULT_FORMAT),
+            alt_format: None,
+            week_numbers: false,
+            timezones: Vec::new(),
+            calendars: Vec::new()
+        }
+    }
+}
+
+/// Smallest unit the displayed format changes with.
+#[derive(Clone, Copy, PartialEq)]
+enum Precision {
+    Seconds,
+    Minutes
+}
 
 pub struct ClockWidget {
-    hours: String,
-    minutes: String
+    /// One label per clock, `None` being the local time.
+    labels: Vec<(Label, Option<Tz>)>,
+    format: String,
+    alt_format: Option<String>,
+    alt: bool,
+    texts: Vec<String>,
+    timer: Option<SourceId>,
+    calendars: Vec<PathBuf>,
+    events: Vec<Event>,
+    event_label: Label,
+    event_text: Option<String>,
+    calendar: Calendar,
+    event_list: gtk::Box,
+    /// Kept alive to reload the events when the calendars change.
+    monitors: Vec<FileMonitor>,
+    debug: bool
+}
+
+/// Used to validate the format arguments, chrono panics when displaying an
+/// invalid format.
+pub fn is_valid_format(format: &str) -> bool {
+    StrftimeItems::new(format).all(|item| item != Item::Error)
+}
+
+fn get_precision(format: &str) -> Precision {
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+        // Skips padding modifiers like `%-S` or `%_S`
+        let mut specifier = chars.next();
+        while let Some('-') | Some('_') | Some('0') = specifier {
+            specifier = chars.next();
+        }
+        match specifier {
+            Some('S') | Some('T') | Some('X') | Some('r') | Some('c') | Some('s') | Some('f') | Some('+') => {
+                return Precision::Seconds;
+            },
+            _ => {}
+        }
+    }
+    Precision::Minutes
 }
 
 impl ClockWidget {
-    fn update(&mut self, label: &Label, force_refresh: bool) {
-        let (current_hours, current_minutes) = ClockWidget::get_time();
-        let ClockWidget { hours, minutes } = &self;
-        if hours != &current_hours
-            || minutes != &current_minutes
-                || force_refresh {
-                    label.set_text(&format!("{}:{}", current_hours, current_minutes));
-                    self.hours = current_hours;
-                    self.minutes = current_minutes;
-                }
-    }
-
-    fn get_time() -> (String, String) {
-        let hours = format!("{}", Local::now().format("%H"));
-        let minutes = format!("{}", Local::now().format("%M"));
-        (hours.to_string(), minutes.to_string())
-    }
-
-    pub fn new() -> Label {
-        let label = Label::new(None);
-        set_label_color(&label, 255, 255, 255);
-        let label_rc = Rc::new(label.clone());
-        let label_clone = label_rc.clone();
-        let (hours, minutes) = ClockWidget::get_time();
-        let mut clock = ClockWidget {
-            hours,
-            minutes
+    fn current_format(&self) -> &str {
+        match (&self.alt_format, self.alt) {
+            (Some(alt_format), true) => alt_format,
+            _ => &self.format
+        }
+    }
+
+    /// All clocks are formatted from the same instant so they never tear at
+    /// a minute boundary.
+    fn update(&mut self, now: DateTime<Utc>, force_refresh: bool) {
+        let format = self.current_format().to_string();
+        let texts: Vec<String> = self.labels.iter().map(|(_, timezone)| match timezone {
+            Some(timezone) => now.with_timezone(timezone).format(&format).to_string(),
+            None => now.with_timezone(&Local).format(&format).to_string()
+        }).collect();
+        for (i, ((label, _), text)) in self.labels.iter().zip(texts.iter()).enumerate() {
+            if self.texts.get(i) != Some(text) || force_refresh {
+                label.set_text(text);
+            }
+        }
+        self.texts = texts;
+        let event_text = get_next_event_text(&self.events, now, Duration::hours(EVENT_HORIZON_HOURS))
+            .map(|event_text| truncate(&event_text, EVENT_WIDTH));
+        if self.event_text != event_text || force_refresh {
+            match &event_text {
+                Some(event_text) => {
+                    self.event_label.set_text(event_text);
+                    self.event_label.show();
+                },
+                None => self.event_label.hide()
+            }
+            self.event_text = event_text;
+        }
+    }
+
+    /// Marks the days with events in the displayed month and lists the
+    /// events of the selected day under the calendar.
+    fn update_calendar(&self) {
+        let (year, month, day) = self.calendar.get_date();
+        self.calendar.clear_marks();
+        let first_day = match NaiveDate::from_ymd_opt(year as i32, month + 1, 1) {
+            Some(first_day) => first_day,
+            None => return
         };
-        ClockWidget::update(&mut clock, &label_clone, true);
-        let tick = move || {
-            ClockWidget::update(&mut clock, &label_clone, false);
-            gtk::Continue(true)
+        let mut last_day = first_day;
+        while last_day.succ().month() == first_day.month() {
+            last_day = last_day.succ();
+        }
+        let events = get_day_occurrences(&self.events, first_day, last_day);
+        let mut date = first_day;
+        while date <= last_day {
+            if events.iter().any(|event| event.is_on(date)) {
+                self.calendar.mark_day(date.day());
+            }
+            date = date.succ();
+        }
+        for child in self.event_list.get_children() {
+            self.event_list.remove(&child);
+        }
+        let selected = match NaiveDate::from_ymd_opt(year as i32, month + 1, day) {
+            Some(selected) => selected,
+            None => return
         };
-        gtk::timeout_add_seconds(1, tick);
-        label
+        for event in events.iter().filter(|event| event.is_on(selected)) {
+            let time = if event.all_day {
+                String::from("All day")
+            } else {
+                event.start.with_timezone(&Local).format("%H:%M").to_string()
+            };
+            let label = Label::new(Some(format!("{}  {}", time, event.summary).as_str()));
+            label.set_xalign(0.0);
+            self.event_list.add(&label);
+            label.show();
+        }
+    }
+
+    fn reload_events(clock: &Rc<RefCell<ClockWidget>>) {
+        let events = {
+            let clock = clock.borrow();
+            load_events(&clock.calendars, clock.debug)
+        };
+        let mut clock = clock.borrow_mut();
+        clock.events = events;
+        clock.update(Utc::now(), false);
+        clock.update_calendar();
+    }
+
+    /// inotify backed monitors on the calendar files and directories.
+    fn watch_calendars(clock: &Rc<RefCell<ClockWidget>>) {
+        let (files, dirs) = {
+            let clock = clock.borrow();
+            let files: Vec<PathBuf> = clock.calendars.iter().filter(|path| !path.is_dir()).cloned().collect();
+            (files, get_calendar_dirs(&clock.calendars))
+        };
+        let mut monitors = Vec::new();
+        for path in &files {
+            match gio::File::new_for_path(path).monitor_file(FileMonitorFlags::NONE, None) {
+                Ok(monitor) => monitors.push(monitor),
+                Err(e) => if clock.borrow().debug { println!("{}", e); }
+            }
+        }
+        for path in &dirs {
+            match gio::File::new_for_path(path).monitor_directory(FileMonitorFlags::NONE, None) {
+                Ok(monitor) => monitors.push(monitor),
+                Err(e) => if clock.borrow().debug { println!("{}", e); }
+            }
+        }
+        for monitor in &monitors {
+            let clock_clone = clock.clone();
+            monitor.connect_changed(move |_, _, _, _| ClockWidget::reload_events(&clock_clone));
+        }
+        clock.borrow_mut().monitors = monitors;
+    }
+
+    /// Wakes up right when the displayed text changes instead of polling.
+    fn schedule(clock: &Rc<RefCell<ClockWidget>>) {
+        let now = Utc::now();
+        let millis = u32::min(now.timestamp_subsec_millis(), 999);
+        let delay = match get_precision(clock.borrow().current_format()) {
+            Precision::Seconds => 1000 - millis,
+            Precision::Minutes => (60 - now.second().min(59)) * 1000 - millis
+        };
+        let clock_clone = clock.clone();
+        let timer = timeout_add(delay, move || {
+            clock_clone.borrow_mut().timer = None;
+            clock_clone.borrow_mut().update(Utc::now(), false);
+            ClockWidget::schedule(&clock_clone);
+            Continue(false)
+        });
+        clock.borrow_mut().timer = Some(timer);
+    }
+
+    fn toggle_format(clock: &Rc<RefCell<ClockWidget>>) {
+        {
+            let mut clock = clock.borrow_mut();
+            if clock.alt_format.is_none() {
+                return;
+            }
+            if let Some(timer) = clock.timer.take() {
+                glib::source_remove(timer);
+            }
+            clock.alt = !clock.alt;
+            clock.update(Utc::now(), true);
+        }
+        ClockWidget::schedule(clock);
+    }
+
+    /// Month calendar under the clock, the popover closes by itself on
+    /// Escape or a click anywhere else.
+    fn build_calendar(event_box: &EventBox, week_numbers: bool) -> (Popover, Calendar, gtk::Box) {
+        let popover = Popover::new(Some(event_box));
+        let popover_box = gtk::Box::new(Vertical, 4);
+        popover.set_position(PositionType::Bottom);
+        let calendar = Calendar::new();
+        let mut options = CalendarDisplayOptions::SHOW_HEADING | CalendarDisplayOptions::SHOW_DAY_NAMES;
+        if week_numbers {
+            options |= CalendarDisplayOptions::SHOW_WEEK_NUMBERS;
+        }
+        calendar.set_display_options(options);
+        calendar.add_events(gdk::EventMask::SCROLL_MASK.bits() as i32);
+        calendar.connect_scroll_event(|calendar, event_scroll| {
+            let step = match event_scroll.get_direction() {
+                gdk::ScrollDirection::Up => -1,
+                gdk::ScrollDirection::Down => 1,
+                gdk::ScrollDirection::Smooth => match event_scroll.get_delta().1 {
+                    delta if delta > 0.0 => 1,
+                    delta if delta < 0.0 => -1,
+                    _ => 0
+                },
+                _ => 0
+            };
+            if step != 0 {
+                let (year, month, _) = calendar.get_date();
+                let months = year as i32 * 12 + month as i32 + step;
+                calendar.select_month((months % 12) as u32, (months / 12) as u32);
+            }
+            Inhibit(true)
+        });
+        let event_list = gtk::Box::new(Vertical, 2);
+        popover_box.add(&calendar);
+        popover_box.add(&event_list);
+        popover.add(&popover_box);
+        popover_box.show_all();
+        (popover, calendar, event_list)
+    }
+
+    /// Every opening starts back on today.
+    fn show_calendar(popover: &Popover, calendar: &Calendar) {
+        let today = Local::now();
+        calendar.select_month(today.month0(), today.year() as u32);
+        calendar.select_day(today.day());
+        popover.popup();
+    }
+
+    pub fn new(settings: &Settings) -> EventBox {
+        let event_box = EventBox::new();
+        let container = gtk::Box::new(Horizontal, 0);
+        event_box.add(&container);
+        let mut labels = Vec::new();
+        let timezones = settings.clock.timezones.iter().map(|timezone| Some(*timezone));
+        for timezone in iter::once(None).chain(timezones) {
+            let label = Label::new(None);
+            set_label_color(&label, 255, 255, 255);
+            if let Some(timezone) = timezone {
+                label.set_margin_start(7);
+                label.set_tooltip_text(Some(timezone.name()));
+            }
+            container.add(&label);
+            labels.push((label, timezone));
+        }
+        let event_label = Label::new(None);
+        event_label.set_margin_start(7);
+        event_label.set_no_show_all(true);
+        set_label_color(&event_label, 255, 255, 255);
+        container.add(&event_label);
+        let (popover, calendar, event_list) = ClockWidget::build_calendar(&event_box, settings.clock.week_numbers);
+        let clock = Rc::new(RefCell::new(ClockWidget {
+            labels,
+            format: settings.clock.format.clone(),
+            alt_format: settings.clock.alt_format.clone(),
+            alt: false,
+            texts: Vec::new(),
+            timer: None,
+            calendars: settings.clock.calendars.clone(),
+            events: Vec::new(),
+            event_label,
+            event_text: None,
+            calendar: calendar.clone(),
+            event_list,
+            monitors: Vec::new(),
+            debug: settings.debug
+        }));
+        if !settings.clock.calendars.is_empty() {
+            ClockWidget::reload_events(&clock);
+            ClockWidget::watch_calendars(&clock);
+            let clock_clone = clock.clone();
+            calendar.connect_day_selected(move |_| clock_clone.borrow().update_calendar());
+            let clock_clone = clock.clone();
+            calendar.connect_month_changed(move |_| clock_clone.borrow().update_calendar());
+        }
+        clock.borrow_mut().update(Utc::now(), true);
+        ClockWidget::schedule(&clock);
+        let clock_clone = clock.clone();
+        event_box.connect_button_press_event(move |_, event_button| {
+            match event_button.get_button() {
+                1 => ClockWidget::show_calendar(&popover, &calendar),
+                3 => ClockWidget::toggle_format(&clock_clone),
+                _ => {}
+            }
+            Inhibit(false)
+        });
+        event_box
     }
 }
@@ -0,0 +1,11 @@
+use std::io;
+use std::process::Command;
+use std::thread;
+
+/// Spawns `command` without waiting for it, a thread reaps it once it exits
+/// so that finished commands do not linger as zombies.
+pub fn spawn_detached(command: &mut Command) -> io::Result<()> {
+    let mut child = command.spawn()?;
+    thread::spawn(move || child.wait());
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use gdk_pixbuf::{InterpType, Pixbuf, PixbufExt};
+use gio::FileExt;
+
+const CACHE_SIZE: usize = 32;
+
+/// A decoded cover, at thumbnail and popup sizes.
+#[derive(Clone)]
+pub struct Cover {
+    pub thumbnail: Pixbuf,
+    pub large: Pixbuf
+}
+
+/// Decoded covers by `mpris:artUrl`. Failures are cached as well so a
+/// broken URL is not read again on every track change.
+pub struct CoverCache {
+    thumbnail_size: i32,
+    large_size: i32,
+    covers: HashMap<String, Option<Cover>>
+}
+
+impl CoverCache {
+    pub fn new(thumbnail_size: i32, large_size: i32) -> CoverCache {
+        CoverCache {
+            thumbnail_size,
+            large_size,
+            covers: HashMap::new()
+        }
+    }
+
+    pub fn get(&mut self, url: &str) -> Option<Cover> {
+        if let Some(cover) = self.covers.get(url) {
+            return cover.clone();
+        }
+        if self.covers.len() >= CACHE_SIZE {
+            self.covers.clear();
+        }
+        let cover = self.load(url);
+        self.covers.insert(url.to_string(), cover.clone());
+        cover
+    }
+
+    /// Only local `file://` covers are read, remote ones would block the bar.
+    fn load(&self, url: &str) -> Option<Cover> {
+        if !url.starts_with("file://") {
+            return None;
+        }
+        let path = gio::File::new_for_uri(url).get_path()?;
+        let large = Pixbuf::new_from_file_at_scale(&path, self.large_size, self.large_size, true).ok()?;
+        let (width, height) = (large.get_width(), large.get_height());
+        let ratio = f64::from(self.thumbnail_size) / f64::from(width.max(height));
+        let thumbnail = large.scale_simple(
+            (f64::from(width) * ratio).round().max(1.0) as i32,
+            (f64::from(height) * ratio).round().max(1.0) as i32,
+            InterpType::Bilinear
+        )?;
+        Some(Cover { thumbnail, large })
+    }
+}
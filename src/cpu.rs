@@ -0,0 +1,309 @@
+use gtk::{
+    timeout_add,
+    ContainerExt,
+    Continue,
+    DrawingArea,
+    Inhibit,
+    Label,
+    LabelExt,
+    WidgetExt
+};
+use gtk::Orientation::Horizontal;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::format::format;
+use crate::paint::{get_threshold_color, set_label_color};
+use crate::Settings;
+
+const REFRESH_RATE: u32 = 1000;
+const DEFAULT_FORMAT: &str = "{icon}{usage}%";
+const ICON: &str = "\u{f2db} ";
+const BAR_GLYPHS: [char; 5] = ['▁', '▂', '▃', '▅', '▇'];
+const SPARKLINE_WIDTH: i32 = 60;
+const SPARKLINE_HEIGHT: i32 = 18;
+/// Samples shown by the sparkline, one per refresh.
+const SPARKLINE_SAMPLES: usize = 30;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CpuMode {
+    /// The format, with the usage and load averages.
+    Text,
+    /// One glyph per core.
+    Bars,
+    /// The recent total usage, drawn next to the text.
+    Sparkline
+}
+
+#[derive(Clone)]
+pub struct CpuSettings {
+    pub enabled: bool,
+    pub mode: CpuMode,
+    /// Placeholders are `{icon}`, `{usage}` (in percent), `{load1}`,
+    /// `{load5}` and `{load15}`.
+    pub format: String,
+    /// Usage percentages turning the module yellow, then red.
+    pub warning: u8,
+    pub critical: u8
+}
+
+impl Default for CpuSettings {
+    fn default() -> CpuSettings {
+        CpuSettings {
+            enabled: false,
+            mode: CpuMode::Text,
+            format: String::from(DEFAULT_FORMAT),
+            warning: 70,
+            critical: 90
+        }
+    }
+}
+
+/// Cumulated jiffies of a `cpu` line of `/proc/stat`.
+#[derive(Clone, Copy)]
+struct CpuTimes {
+    idle: u64,
+    total: u64
+}
+
+pub struct CpuWidget {
+    procfs_root: PathBuf,
+    mode: CpuMode,
+    format: String,
+    warning: f64,
+    critical: f64,
+    /// Total first, then every core.
+    last_times: Vec<CpuTimes>,
+    history: Rc<RefCell<VecDeque<f64>>>,
+    sparkline: DrawingArea,
+    text: String,
+    color: Option<(u8, u8, u8)>,
+    tooltip: String
+}
+
+impl CpuTimes {
+    /// Usage in percent since `previous`.
+    fn usage(&self, previous: &CpuTimes) -> f64 {
+        let total = self.total.saturating_sub(previous.total);
+        let idle = self.idle.saturating_sub(previous.idle);
+        if total == 0 {
+            return 0.0;
+        }
+        total.saturating_sub(idle) as f64 * 100.0 / total as f64
+    }
+}
+
+fn get_bar_glyph(usage: f64) -> char {
+    let index = (usage / 100.0 * BAR_GLYPHS.len() as f64) as usize;
+    BAR_GLYPHS[index.min(BAR_GLYPHS.len() - 1)]
+}
+
+/// Guest time is already counted in user time, so only the first eight
+/// fields are summed.
+fn get_times(procfs_root: &Path) -> Vec<CpuTimes> {
+    let content = match fs::read_to_string(procfs_root.join("stat")) {
+        Ok(content) => content,
+        Err(_) => return Vec::new()
+    };
+    content.lines()
+        .filter(|line| line.starts_with("cpu"))
+        .map(|line| {
+            let values: Vec<u64> = line.split_whitespace()
+                .skip(1)
+                .take(8)
+                .filter_map(|value| value.parse().ok())
+                .collect();
+            CpuTimes {
+                // idle and iowait
+                idle: values.iter().skip(3).take(2).sum(),
+                total: values.iter().sum()
+            }
+        })
+        .collect()
+}
+
+/// Usages since `last_times`, total first, then every core.
+fn get_usages(times: &[CpuTimes], last_times: &[CpuTimes]) -> Vec<f64> {
+    times.iter()
+        .zip(last_times.iter())
+        .map(|(times, last_times)| times.usage(last_times))
+        .collect()
+}
+
+fn get_load(procfs_root: &Path) -> Vec<String> {
+    fs::read_to_string(procfs_root.join("loadavg"))
+        .map(|content| content.split_whitespace().take(3).map(String::from).collect())
+        .unwrap_or_default()
+}
+
+impl CpuWidget {
+    fn update(&mut self, label: &Label, force_refresh: bool) {
+        let times = get_times(&self.procfs_root);
+        let usages = get_usages(&times, &self.last_times);
+        self.last_times = times;
+        let usage = usages.first().cloned().unwrap_or(0.0);
+        let load = get_load(&self.procfs_root);
+        let text = match self.mode {
+            CpuMode::Bars => usages.iter().skip(1).map(|usage| get_bar_glyph(*usage)).collect(),
+            CpuMode::Text | CpuMode::Sparkline => {
+                format(&self.format, |key| match key {
+                    "icon" => Some(ICON.to_string()),
+                    "usage" => Some(format!("{:.0}", usage)),
+                    "load1" => load.get(0).cloned(),
+                    "load5" => load.get(1).cloned(),
+                    "load15" => load.get(2).cloned(),
+                    _ => None
+                })
+            }
+        };
+        if self.text != text || force_refresh {
+            label.set_text(&text);
+            self.text = text;
+        }
+        let color = get_threshold_color(usage, self.warning, self.critical);
+        if self.color != Some(color) || force_refresh {
+            let (r, g, b) = color;
+            set_label_color(label, r, g, b);
+            self.color = Some(color);
+        }
+        let mut tooltip = format!("Load: {}", load.join(" "));
+        for (i, usage) in usages.iter().skip(1).enumerate() {
+            tooltip.push_str(&format!("\nCore {}: {:.0}%", i, usage));
+        }
+        if self.tooltip != tooltip || force_refresh {
+            label.set_tooltip_text(Some(tooltip.as_str()));
+            self.tooltip = tooltip;
+        }
+        if self.mode == CpuMode::Sparkline {
+            let mut history = self.history.borrow_mut();
+            if history.len() == SPARKLINE_SAMPLES {
+                history.pop_front();
+            }
+            history.push_back(usage);
+            self.sparkline.queue_draw();
+        }
+    }
+
+    /// Newest sample on the right, in the color of the current usage.
+    fn build_sparkline(history: &Rc<RefCell<VecDeque<f64>>>, warning: f64, critical: f64) -> DrawingArea {
+        let sparkline = DrawingArea::new();
+        sparkline.set_size_request(SPARKLINE_WIDTH, SPARKLINE_HEIGHT);
+        sparkline.set_margin_end(7);
+        let history_clone = history.clone();
+        sparkline.connect_draw(move |widget, ctx| {
+            let width = f64::from(widget.get_allocated_width());
+            let height = f64::from(widget.get_allocated_height());
+            let history = history_clone.borrow();
+            let step = width / (SPARKLINE_SAMPLES - 1) as f64;
+            let offset = width - step * history.len().saturating_sub(1) as f64;
+            for (i, usage) in history.iter().enumerate() {
+                let (x, y) = (offset + step * i as f64, height - height * usage / 100.0);
+                if i == 0 {
+                    ctx.move_to(x, y);
+                } else {
+                    ctx.line_to(x, y);
+                }
+            }
+            let (r, g, b) = get_threshold_color(history.back().cloned().unwrap_or(0.0), warning, critical);
+            ctx.set_source_rgb(f64::from(r) / 255.0, f64::from(g) / 255.0, f64::from(b) / 255.0);
+            ctx.set_line_width(1.5);
+            ctx.stroke();
+            Inhibit(false)
+        });
+        sparkline
+    }
+
+    pub fn new(settings: &Settings) -> gtk::Box {
+        let container = gtk::Box::new(Horizontal, 0);
+        if !settings.cpu.enabled {
+            container.set_no_show_all(true);
+            container.hide();
+            return container;
+        }
+        let label = Label::new(None);
+        label.set_margin_end(7);
+        container.add(&label);
+        let (warning, critical) = (f64::from(settings.cpu.warning), f64::from(settings.cpu.critical));
+        let history = Rc::new(RefCell::new(VecDeque::new()));
+        let sparkline = CpuWidget::build_sparkline(&history, warning, critical);
+        if settings.cpu.mode == CpuMode::Sparkline {
+            container.add(&sparkline);
+        }
+        let mut cpu = CpuWidget {
+            procfs_root: settings.procfs_root.clone(),
+            mode: settings.cpu.mode,
+            format: settings.cpu.format.clone(),
+            warning,
+            critical,
+            // A first sample, for the first refresh to have usages
+            last_times: get_times(&settings.procfs_root),
+            history,
+            sparkline,
+            text: String::new(),
+            color: None,
+            tooltip: String::new()
+        };
+        cpu.update(&label, true);
+        timeout_add(REFRESH_RATE, move || {
+            cpu.update(&label, false);
+            Continue(true)
+        });
+        container
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn stat_times() {
+        let times = get_times(&fixtures::procfs("thinkpad-t480"));
+        assert_eq!(times.len(), 9);
+        assert_eq!((times[0].total, times[0].idle), (82432146, 70101617));
+        assert_eq!((times[1].total, times[1].idle), (10348968, 8734061));
+        assert!(get_times(&fixtures::procfs("dell-xps-13")).is_empty());
+    }
+
+    #[test]
+    fn usage_deltas() {
+        let last_times = get_times(&fixtures::procfs("thinkpad-t480"));
+        let mut times = last_times.clone();
+        times[0].total += 800;
+        times[0].idle += 600;
+        times[1].total += 100;
+        times[1].idle += 10;
+        // An idle core
+        times[2].total += 100;
+        times[2].idle += 100;
+        let usages = get_usages(&times, &last_times);
+        assert_eq!(usages.len(), 9);
+        assert_eq!(usages[0], 25.0);
+        assert_eq!(usages[1], 90.0);
+        assert_eq!(usages[2], 0.0);
+        // No time elapsed
+        assert_eq!(usages[3], 0.0);
+        // Nothing to compare the first sample to
+        assert!(get_usages(&times, &[]).is_empty());
+    }
+
+    #[test]
+    fn bar_glyph_buckets() {
+        assert_eq!(get_bar_glyph(0.0), '▁');
+        assert_eq!(get_bar_glyph(19.9), '▁');
+        assert_eq!(get_bar_glyph(20.0), '▂');
+        assert_eq!(get_bar_glyph(59.9), '▃');
+        assert_eq!(get_bar_glyph(60.0), '▅');
+        assert_eq!(get_bar_glyph(80.0), '▇');
+        assert_eq!(get_bar_glyph(100.0), '▇');
+    }
+
+    #[test]
+    fn load_averages() {
+        assert_eq!(get_load(&fixtures::procfs("thinkpad-t480")), vec!["0.52", "0.58", "0.59"]);
+        assert!(get_load(&fixtures::procfs("dell-xps-13")).is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+use gtk::{
+    ContainerExt,
+    Continue,
+    EventBox,
+    Inhibit,
+    Label,
+    LabelExt,
+    WidgetExt
+};
+use gtk::Orientation::Horizontal;
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use crate::command::spawn_detached;
+use crate::format::{format, format_bytes};
+use crate::paint::{get_threshold_color, set_label_color};
+use crate::Settings;
+
+/// Disk usage changes slowly.
+const REFRESH_RATE: Duration = Duration::from_secs(10);
+const DEFAULT_FORMAT: &str = "{icon}{free}";
+const ICON: &str = "\u{f0a0} ";
+
+#[derive(Clone)]
+pub struct Mount {
+    pub path: PathBuf,
+    /// Overrides the format of the module for this mount point.
+    pub format: Option<String>
+}
+
+impl Mount {
+    /// Parses `<path>[:<format>]`. The format starts after the last colon
+    /// outside of braces, so a mount point containing colons needs a
+    /// trailing one, e.g. `/mnt/a:b:`.
+    pub fn parse(value: &str) -> Mount {
+        let mut depth = 0;
+        let mut separator = None;
+        for (i, c) in value.char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                ':' if depth <= 0 => separator = Some(i),
+                _ => ()
+            }
+        }
+        match separator {
+            Some(i) => Mount {
+                path: PathBuf::from(&value[..i]),
+                format: Some(&value[i + 1..]).filter(|format| !format.is_empty()).map(String::from)
+            },
+            None => Mount { path: PathBuf::from(value), format: None }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DiskSettings {
+    /// One label per mount point, the module is hidden without any.
+    pub mounts: Vec<Mount>,
+    /// Placeholders are `{icon}`, `{path}`, `{free}`, `{used}`, `{total}`
+    /// and `{percent}` (used, as `df` counts it).
+    pub format: String,
+    /// Used space percentages turning a mount point yellow, then red.
+    pub warning: u8,
+    pub critical: u8,
+    /// Command a click opens the mount point with, the path being appended.
+    pub file_manager: String
+}
+
+impl Default for DiskSettings {
+    fn default() -> DiskSettings {
+        DiskSettings {
+            mounts: Vec::new(),
+            format: String::from(DEFAULT_FORMAT),
+            warning: 80,
+            critical: 90,
+            file_manager: String::from("xdg-open")
+        }
+    }
+}
+
+/// Sizes in bytes.
+struct DiskUsage {
+    free: u64,
+    used: u64,
+    total: u64
+}
+
+struct DiskLabel {
+    path: PathBuf,
+    format: String,
+    label: Label,
+    text: String,
+    color: Option<(u8, u8, u8)>
+}
+
+pub struct DiskWidget {
+    labels: Vec<DiskLabel>,
+    warning: f64,
+    critical: f64,
+    /// Usages sent by the worker threads, with the index of their label.
+    rx: Receiver<(usize, Option<DiskUsage>)>
+}
+
+thread_local!(
+    static DISK_WIDGETS: RefCell<Vec<DiskWidget>> = RefCell::new(Vec::new())
+);
+
+/// `free` is what unprivileged users can still write, blocks reserved to
+/// root count as neither free nor used.
+fn get_usage(path: &Path) -> Option<DiskUsage> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let fragment_size = stat.f_frsize as u64;
+    let total = stat.f_blocks as u64 * fragment_size;
+    let free = stat.f_bavail as u64 * fragment_size;
+    let used = (stat.f_blocks as u64).saturating_sub(stat.f_bfree as u64) * fragment_size;
+    Some(DiskUsage { free, used, total })
+}
+
+/// Through `sh`, so the command can have arguments, the path being passed
+/// as a positional parameter to need no quoting.
+fn open_file_manager(file_manager: &str, path: &Path, debug: bool) {
+    let result = spawn_detached(Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", file_manager))
+        .arg("sh")
+        .arg(path));
+    if let Err(e) = result {
+        if debug { println!("{}", e); }
+    }
+}
+
+/// `statvfs` blocks for as long as a network mount is unreachable, so each
+/// mount point is polled by its own thread and only its label goes stale.
+fn watch(id: usize, index: usize, path: PathBuf, tx: Sender<(usize, Option<DiskUsage>)>) {
+    thread::spawn(move || loop {
+        if tx.send((index, get_usage(&path))).is_err() {
+            return;
+        }
+        glib::idle_add(move || {
+            DiskWidget::receive(id);
+            Continue(false)
+        });
+        thread::sleep(REFRESH_RATE);
+    });
+}
+
+impl DiskWidget {
+    fn receive(id: usize) {
+        DISK_WIDGETS.with(|disk_widgets| {
+            if let Some(disk_widget) = disk_widgets.borrow_mut().get_mut(id) {
+                disk_widget.update();
+            }
+        });
+    }
+
+    fn update(&mut self) {
+        for (index, usage) in self.rx.try_iter() {
+            let disk_label = match self.labels.get_mut(index) {
+                Some(disk_label) => disk_label,
+                None => continue
+            };
+            let (text, percent) = match usage {
+                Some(usage) => {
+                    let percent = if usage.used + usage.free == 0 {
+                        0.0
+                    } else {
+                        usage.used as f64 * 100.0 / (usage.used + usage.free) as f64
+                    };
+                    let text = format(&disk_label.format, |key| match key {
+                        "icon" => Some(ICON.to_string()),
+                        "path" => Some(disk_label.path.display().to_string()),
+                        "free" => Some(format_bytes(usage.free)),
+                        "used" => Some(format_bytes(usage.used)),
+                        "total" => Some(format_bytes(usage.total)),
+                        "percent" => Some(format!("{:.0}", percent)),
+                        _ => None
+                    });
+                    (text, percent)
+                },
+                None => (format!("{}{} ?", ICON, disk_label.path.display()), 0.0)
+            };
+            if disk_label.text != text {
+                disk_label.label.set_text(&text);
+                disk_label.text = text;
+            }
+            let color = get_threshold_color(percent, self.warning, self.critical);
+            if disk_label.color != Some(color) {
+                let (r, g, b) = color;
+                set_label_color(&disk_label.label, r, g, b);
+                disk_label.color = Some(color);
+            }
+        }
+    }
+
+    /// A click on a mount point opens it in the file manager.
+    pub fn new(settings: &Settings) -> gtk::Box {
+        let container = gtk::Box::new(Horizontal, 0);
+        if settings.disk.mounts.is_empty() {
+            container.set_no_show_all(true);
+            container.hide();
+            return container;
+        }
+        let (tx, rx) = channel();
+        let id = DISK_WIDGETS.with(|disk_widgets| disk_widgets.borrow().len());
+        let mut labels = Vec::new();
+        for (index, mount) in settings.disk.mounts.iter().enumerate() {
+            let event_box = EventBox::new();
+            let label = Label::new(None);
+            label.set_margin_end(7);
+            label.set_tooltip_text(Some(mount.path.display().to_string().as_str()));
+            event_box.add(&label);
+            container.add(&event_box);
+            let (file_manager, path, debug) = (settings.disk.file_manager.clone(), mount.path.clone(), settings.debug);
+            event_box.connect_button_press_event(move |_, event_button| {
+                if event_button.get_button() == 1 {
+                    open_file_manager(&file_manager, &path, debug);
+                }
+                Inhibit(false)
+            });
+            watch(id, index, mount.path.clone(), tx.clone());
+            labels.push(DiskLabel {
+                path: mount.path.clone(),
+                format: mount.format.clone().unwrap_or_else(|| settings.disk.format.clone()),
+                label,
+                text: String::new(),
+                color: None
+            });
+        }
+        let disk_widget = DiskWidget {
+            labels,
+            warning: f64::from(settings.disk.warning),
+            critical: f64::from(settings.disk.critical),
+            rx
+        };
+        DISK_WIDGETS.with(|disk_widgets| disk_widgets.borrow_mut().push(disk_widget));
+        container
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_and_format() {
+        let mount = Mount::parse("/home");
+        assert_eq!(mount.path, PathBuf::from("/home"));
+        assert_eq!(mount.format, None);
+        let mount = Mount::parse("/home:{free} free");
+        assert_eq!(mount.path, PathBuf::from("/home"));
+        assert_eq!(mount.format.as_ref().map(String::as_str), Some("{free} free"));
+        let mount = Mount::parse("/mnt/a:b:{mount:8}");
+        assert_eq!(mount.path, PathBuf::from("/mnt/a:b"));
+        assert_eq!(mount.format.as_ref().map(String::as_str), Some("{mount:8}"));
+        let mount = Mount::parse("/mnt/a:b:");
+        assert_eq!(mount.path, PathBuf::from("/mnt/a:b"));
+        assert_eq!(mount.format, None);
+    }
+}
@@ -0,0 +1,762 @@
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+
+/// Bounds the periods looked at for one recurring event, against rules
+/// matching nothing for a long time.
+const MAX_PERIODS: u32 = 10_000;
+
+/// A `VEVENT` of a local iCalendar file, its first occurrence when it
+/// recurs. `get_occurrences` expands recurring events over a window.
+#[derive(Clone)]
+pub struct Event {
+    pub summary: String,
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    pub all_day: bool,
+    recurrence: Option<Recurrence>
+}
+
+/// Where the wall clock time of a date-time is, recurrences keep it across
+/// daylight saving time changes.
+#[derive(Clone, Copy)]
+enum Zone {
+    Utc,
+    /// Floating date-times and dates.
+    Local,
+    Tz(Tz)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    /// Yearly rules are monthly ones with 12 times the interval.
+    Monthly
+}
+
+/// A `RRULE` and the `EXDATE`s of an event. Weeks start on Monday, `BYDAY`
+/// takes ordinals like `-1FR` in monthly rules. Rules with other `BY` parts
+/// are not expanded, only their first occurrence shows up.
+#[derive(Clone)]
+struct Recurrence {
+    /// `DTSTART` in `zone`.
+    start: NaiveDateTime,
+    duration: Option<Duration>,
+    zone: Zone,
+    frequency: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    /// Last possible start, included.
+    until: Option<DateTime<Utc>>,
+    /// Ordinal of the weekday in the month, 0 for all of them.
+    by_day: Vec<(i32, Weekday)>,
+    exdates: Vec<DateTime<Utc>>
+}
+
+impl Event {
+    /// Whether the event happens at some point of the local `date`.
+    pub fn is_on(&self, date: NaiveDate) -> bool {
+        let start = self.start.with_timezone(&Local).naive_local().date();
+        let end = self.end
+            .map(|end| end.with_timezone(&Local).naive_local())
+            // Ends are exclusive, an all day event ends at the next midnight
+            .map(|end| (end - Duration::seconds(1)).date())
+            .unwrap_or(start);
+        start <= date && date <= end.max(start)
+    }
+}
+
+impl Zone {
+    fn to_utc(self, naive: &NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Zone::Utc => Some(DateTime::from_utc(*naive, Utc)),
+            Zone::Local => Local.from_local_datetime(naive).earliest().map(|time| time.with_timezone(&Utc)),
+            Zone::Tz(timezone) => timezone.from_local_datetime(naive).earliest().map(|time| time.with_timezone(&Utc))
+        }
+    }
+}
+
+impl Recurrence {
+    fn parse(rule: &str, start: NaiveDateTime, end: Option<NaiveDateTime>, zone: Zone) -> Option<Recurrence> {
+        let mut frequency = None;
+        let mut recurrence = Recurrence {
+            start,
+            duration: end.map(|end| end - start),
+            zone,
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: Vec::new(),
+            exdates: Vec::new()
+        };
+        for part in rule.split(';') {
+            let mut tokens = part.splitn(2, '=');
+            let (key, value) = match (tokens.next(), tokens.next()) {
+                (Some(key), Some(value)) => (key.to_uppercase(), value.trim()),
+                _ => continue
+            };
+            match key.as_str() {
+                "FREQ" => frequency = match value.to_uppercase().as_str() {
+                    "DAILY" => Some((Frequency::Daily, 1)),
+                    "WEEKLY" => Some((Frequency::Weekly, 1)),
+                    "MONTHLY" => Some((Frequency::Monthly, 1)),
+                    "YEARLY" => Some((Frequency::Monthly, 12)),
+                    _ => return None
+                },
+                "INTERVAL" => recurrence.interval = value.parse().ok().filter(|interval| *interval > 0)?,
+                "COUNT" => recurrence.count = Some(value.parse().ok()?),
+                "UNTIL" => recurrence.until = Some(parse_until(value)?),
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        recurrence.by_day.push(parse_weekday(day)?);
+                    }
+                },
+                "WKST" => {},
+                _ => return None
+            }
+        }
+        let (frequency, months) = frequency?;
+        if months > 1 && !recurrence.by_day.is_empty() {
+            return None;
+        }
+        recurrence.frequency = frequency;
+        recurrence.interval = recurrence.interval.checked_mul(months)?;
+        Some(recurrence)
+    }
+
+    /// The dates of the `period`th day, week or month of the rule, some of
+    /// them possibly before the start.
+    fn get_period(&self, period: u32) -> Vec<NaiveDateTime> {
+        let date = self.start.date();
+        let steps = i64::from(period) * i64::from(self.interval);
+        let mut dates: Vec<NaiveDate> = match self.frequency {
+            Frequency::Daily => {
+                let day = date + Duration::days(steps);
+                if self.by_day.is_empty() || self.by_day.iter().any(|(_, weekday)| *weekday == day.weekday()) {
+                    vec![day]
+                } else {
+                    Vec::new()
+                }
+            },
+            Frequency::Weekly => {
+                let monday = date - Duration::days(i64::from(date.weekday().num_days_from_monday()))
+                    + Duration::weeks(steps);
+                if self.by_day.is_empty() {
+                    vec![monday + Duration::days(i64::from(date.weekday().num_days_from_monday()))]
+                } else {
+                    self.by_day.iter()
+                        .map(|(_, weekday)| monday + Duration::days(i64::from(weekday.num_days_from_monday())))
+                        .collect()
+                }
+            },
+            Frequency::Monthly => {
+                let months = i64::from(date.year()) * 12 + i64::from(date.month0()) + steps;
+                let (year, month) = ((months / 12) as i32, (months % 12) as u32 + 1);
+                if self.by_day.is_empty() {
+                    // Months without the day are skipped
+                    NaiveDate::from_ymd_opt(year, month, date.day()).into_iter().collect()
+                } else {
+                    self.by_day.iter()
+                        .flat_map(|(ordinal, weekday)| get_month_weekdays(year, month, *ordinal, *weekday))
+                        .collect()
+                }
+            }
+        };
+        dates.sort();
+        dates.dedup();
+        dates.into_iter().map(|date| date.and_time(self.start.time())).collect()
+    }
+
+    /// Skips the periods ending before `from`, unless the occurrences need
+    /// to be counted.
+    fn get_first_period(&self, start: DateTime<Utc>, from: DateTime<Utc>) -> u32 {
+        if self.count.is_some() {
+            return 0;
+        }
+        // A day of margin for the time zones
+        let days = (from - start - self.duration.unwrap_or_else(Duration::zero)).num_days() - 1;
+        let period_days = i64::from(self.interval) * match self.frequency {
+            Frequency::Daily => 1,
+            Frequency::Weekly => 7,
+            Frequency::Monthly => 31
+        };
+        (days.max(0) / period_days).min(i64::from(u32::max_value())) as u32
+    }
+
+    /// Starts and ends of the occurrences overlapping `from` to `to`.
+    fn get_occurrences(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<(DateTime<Utc>, Option<DateTime<Utc>>)> {
+        let mut occurrences = Vec::new();
+        let start = match self.zone.to_utc(&self.start) {
+            Some(start) => start,
+            None => return occurrences
+        };
+        let first_period = self.get_first_period(start, from);
+        let mut count = 0;
+        for period in first_period..first_period.saturating_add(MAX_PERIODS) {
+            for occurrence in self.get_period(period) {
+                if occurrence < self.start {
+                    continue;
+                }
+                let occurrence_start = match self.zone.to_utc(&occurrence) {
+                    Some(occurrence_start) => occurrence_start,
+                    None => continue
+                };
+                if occurrence_start >= to || self.until.map_or(false, |until| occurrence_start > until) {
+                    return occurrences;
+                }
+                count += 1;
+                if self.count.map_or(false, |max_count| count > max_count) {
+                    return occurrences;
+                }
+                if self.exdates.contains(&occurrence_start) {
+                    continue;
+                }
+                let occurrence_end = self.duration.and_then(|duration| self.zone.to_utc(&(occurrence + duration)));
+                if overlaps(occurrence_start, occurrence_end, from, to) {
+                    occurrences.push((occurrence_start, occurrence_end));
+                }
+            }
+        }
+        occurrences
+    }
+}
+
+/// The `weekday`s of a month, only the `ordinal`th one unless it is 0,
+/// counting from the end when negative.
+fn get_month_weekdays(year: i32, month: u32, ordinal: i32, weekday: Weekday) -> Vec<NaiveDate> {
+    let mut dates = Vec::new();
+    let mut date = match NaiveDate::from_ymd_opt(year, month, 1) {
+        Some(date) => date,
+        None => return dates
+    };
+    while date.month() == month {
+        if date.weekday() == weekday {
+            dates.push(date);
+        }
+        date = date.succ();
+    }
+    match ordinal {
+        0 => dates,
+        ordinal if ordinal > 0 => dates.into_iter().nth(ordinal as usize - 1).into_iter().collect(),
+        ordinal => dates.into_iter().rev().nth(-ordinal as usize - 1).into_iter().collect()
+    }
+}
+
+fn overlaps(start: DateTime<Utc>, end: Option<DateTime<Utc>>, from: DateTime<Utc>, to: DateTime<Utc>) -> bool {
+    start < to && (start >= from || end.map_or(false, |end| end > from))
+}
+
+/// The occurrences of the events overlapping `from` to `to`, sorted by
+/// start.
+pub fn get_occurrences(events: &[Event], from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Event> {
+    let mut occurrences = Vec::new();
+    for event in events {
+        match &event.recurrence {
+            Some(recurrence) => {
+                occurrences.extend(recurrence.get_occurrences(from, to).into_iter().map(|(start, end)| Event {
+                    summary: event.summary.clone(),
+                    start,
+                    end,
+                    all_day: event.all_day,
+                    recurrence: None
+                }));
+            },
+            None => if overlaps(event.start, event.end, from, to) {
+                occurrences.push(event.clone());
+            }
+        }
+    }
+    occurrences.sort_by_key(|event| event.start);
+    occurrences
+}
+
+/// The occurrences of the events on the local days from `first` to `last`.
+pub fn get_day_occurrences(events: &[Event], first: NaiveDate, last: NaiveDate) -> Vec<Event> {
+    let midnight = |date: NaiveDate| Zone::Local.to_utc(&date.and_hms(0, 0, 0));
+    match (midnight(first), midnight(last.succ())) {
+        (Some(from), Some(to)) => get_occurrences(events, from, to),
+        _ => Vec::new()
+    }
+}
+
+/// Reads `.ics` files, and directories of them as in vdir storages where
+/// each collection is a subdirectory. Returns the events sorted by start.
+pub fn load_events(paths: &[PathBuf], debug: bool) -> Vec<Event> {
+    let mut events = Vec::new();
+    for path in get_calendar_files(paths) {
+        match fs::read_to_string(&path) {
+            Ok(content) => events.extend(parse_calendar(&content)),
+            Err(e) => if debug { println!("{}: {}", path.display(), e); }
+        }
+    }
+    events.sort_by_key(|event| event.start);
+    events
+}
+
+/// The directories to watch for changes, collections included.
+pub fn get_calendar_dirs(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for path in paths.iter().filter(|path| path.is_dir()) {
+        dirs.push(path.clone());
+        dirs.extend(read_dir(path).into_iter().filter(|path| path.is_dir()));
+    }
+    dirs
+}
+
+fn get_calendar_files(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            for entry in read_dir(path) {
+                if entry.is_dir() {
+                    files.extend(read_dir(&entry).into_iter().filter(|file| is_calendar_file(file)));
+                } else if is_calendar_file(&entry) {
+                    files.push(entry);
+                }
+            }
+        } else {
+            files.push(path.clone());
+        }
+    }
+    files
+}
+
+fn read_dir(path: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(_) => Vec::new()
+    }
+}
+
+fn is_calendar_file(path: &Path) -> bool {
+    path.extension().map_or(false, |extension| extension == "ics")
+}
+
+/// Lines starting with a space or a tab continue the previous one.
+fn unfold(content: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in content.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(&line[1..]);
+                continue;
+            }
+        }
+        lines.push(line.to_string());
+    }
+    lines
+}
+
+/// Splits `DTSTART;TZID=Europe/Paris:20190301T090000` into its name, its
+/// parameters and its value. Quoted parameters can contain colons.
+fn split_property(line: &str) -> Option<(&str, &str, &str)> {
+    let mut quoted = false;
+    let mut value_index = None;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ':' if !quoted => {
+                value_index = Some(i);
+                break;
+            },
+            _ => {}
+        }
+    }
+    let value_index = value_index?;
+    let (name_params, value) = (&line[..value_index], &line[value_index + 1..]);
+    match name_params.find(';') {
+        Some(i) => Some((&name_params[..i], &name_params[i + 1..], value)),
+        None => Some((name_params, "", value))
+    }
+}
+
+fn get_param<'a>(params: &'a str, key: &str) -> Option<&'a str> {
+    params.split(';').find_map(|param| {
+        let mut tokens = param.splitn(2, '=');
+        match (tokens.next(), tokens.next()) {
+            (Some(name), Some(value)) if name.eq_ignore_ascii_case(key) => Some(value.trim_matches('"')),
+            _ => None
+        }
+    })
+}
+
+fn unescape(value: &str) -> String {
+    let mut output = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            output.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => output.push(' '),
+            Some(escaped) => output.push(escaped),
+            None => {}
+        }
+    }
+    output
+}
+
+/// Dates are all day events starting at the local midnight, date-times are
+/// in UTC with a trailing `Z`, in their `TZID` or floating in local time.
+/// Returns the wall clock time, its zone and whether it is a date.
+fn parse_date_time(params: &str, value: &str) -> Option<(NaiveDateTime, Zone, bool)> {
+    let value = value.trim();
+    if get_param(params, "VALUE") == Some("DATE") || value.len() == 8 {
+        let date = NaiveDate::parse_from_str(value, "%Y%m%d").ok()?;
+        return Some((date.and_hms(0, 0, 0), Zone::Local, true));
+    }
+    if value.ends_with('Z') {
+        let naive = NaiveDateTime::parse_from_str(&value[..value.len() - 1], "%Y%m%dT%H%M%S").ok()?;
+        return Some((naive, Zone::Utc, false));
+    }
+    let naive = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok()?;
+    let zone = match get_param(params, "TZID").and_then(|tzid| tzid.parse::<Tz>().ok()) {
+        Some(timezone) => Zone::Tz(timezone),
+        None => Zone::Local
+    };
+    Some((naive, zone, false))
+}
+
+/// `EXDATE` and `RECURRENCE-ID` values, comma separated for the former.
+fn parse_instants(params: &str, value: &str) -> Vec<DateTime<Utc>> {
+    value.split(',')
+        .filter_map(|value| parse_date_time(params, value))
+        .filter_map(|(naive, zone, _)| zone.to_utc(&naive))
+        .collect()
+}
+
+/// An `UNTIL` date includes the whole day.
+fn parse_until(value: &str) -> Option<DateTime<Utc>> {
+    let (naive, zone, date) = parse_date_time("", value)?;
+    if date {
+        zone.to_utc(&(naive + Duration::days(1))).map(|until| until - Duration::seconds(1))
+    } else {
+        zone.to_utc(&naive)
+    }
+}
+
+/// `PT1H30M`, `P2D`, `P1W`... Added to the wall clock time of the start.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (sign, value) = match value.chars().next()? {
+        '-' => (-1, &value[1..]),
+        '+' => (1, &value[1..]),
+        _ => (1, value)
+    };
+    if !value.starts_with('P') || value.len() < 3 {
+        return None;
+    }
+    let mut duration = Duration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in value[1..].chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' if number.is_empty() => in_time = true,
+            _ => {
+                let count: i64 = mem::replace(&mut number, String::new()).parse().ok()?;
+                duration = duration + match (c, in_time) {
+                    ('W', false) => Duration::weeks(count),
+                    ('D', false) => Duration::days(count),
+                    ('H', true) => Duration::hours(count),
+                    ('M', true) => Duration::minutes(count),
+                    ('S', true) => Duration::seconds(count),
+                    _ => return None
+                };
+            }
+        }
+    }
+    if number.is_empty() { Some(duration * sign) } else { None }
+}
+
+/// `MO`, `2TU`, `-1FR`...
+fn parse_weekday(day: &str) -> Option<(i32, Weekday)> {
+    let day = day.trim();
+    if day.len() < 2 || !day.is_char_boundary(day.len() - 2) {
+        return None;
+    }
+    let (ordinal, weekday) = day.split_at(day.len() - 2);
+    let weekday = match weekday.to_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return None
+    };
+    let ordinal = match ordinal {
+        "" => 0,
+        ordinal => ordinal.trim_start_matches('+').parse().ok()?
+    };
+    Some((ordinal, weekday))
+}
+
+fn parse_calendar(content: &str) -> Vec<Event> {
+    let mut events = Vec::new();
+    // A `VEVENT` with a `RECURRENCE-ID` moves or changes one occurrence of
+    // the recurring event with the same `UID`
+    let mut uids = Vec::new();
+    let mut overrides = Vec::new();
+    // Nested components, an alarm inside an event has a summary too
+    let mut components: Vec<String> = Vec::new();
+    let (mut summary, mut start, mut end, mut duration) = (None, None, None, None);
+    let (mut uid, mut rule): (Option<String>, Option<String>) = (None, None);
+    let (mut exdates, mut recurrence_id) = (Vec::new(), None);
+    for line in unfold(content) {
+        let (name, params, value) = match split_property(&line) {
+            Some(property) => property,
+            None => continue
+        };
+        let in_event = components.last().map(String::as_str) == Some("VEVENT");
+        match (name.to_uppercase().as_str(), value) {
+            ("BEGIN", "VEVENT") => {
+                components.push(String::from("VEVENT"));
+                summary = None;
+                start = None;
+                end = None;
+                duration = None;
+                uid = None;
+                rule = None;
+                exdates.clear();
+                recurrence_id = None;
+            },
+            ("END", "VEVENT") if in_event => {
+                components.pop();
+                let (start, zone, all_day): (NaiveDateTime, Zone, bool) = match start.take() {
+                    Some(start) => start,
+                    None => continue
+                };
+                let start_instant = match zone.to_utc(&start) {
+                    Some(start_instant) => start_instant,
+                    None => continue
+                };
+                let end: Option<(NaiveDateTime, Zone, bool)> = end.take()
+                    .or_else(|| duration.take().map(|duration| (start + duration, zone, all_day)));
+                if let (Some(uid), Some(recurrence_id)) = (&uid, recurrence_id.take()) {
+                    overrides.push((uid.clone(), recurrence_id));
+                }
+                let mut recurrence = rule.take()
+                    .and_then(|rule| Recurrence::parse(&rule, start, end.map(|(end, _, _)| end), zone));
+                if let Some(recurrence) = &mut recurrence {
+                    recurrence.exdates = mem::replace(&mut exdates, Vec::new());
+                }
+                uids.push(uid.take());
+                events.push(Event {
+                    summary: summary.take().unwrap_or_default(),
+                    start: start_instant,
+                    end: end.and_then(|(end, zone, _)| zone.to_utc(&end)),
+                    all_day,
+                    recurrence
+                });
+            },
+            ("BEGIN", component) => components.push(component.to_uppercase()),
+            ("END", _) => {
+                components.pop();
+            },
+            ("SUMMARY", value) if in_event => summary = Some(unescape(value)),
+            ("DTSTART", value) if in_event => start = parse_date_time(params, value),
+            ("DTEND", value) if in_event => end = parse_date_time(params, value),
+            ("DURATION", value) if in_event => duration = parse_duration(value),
+            ("UID", value) if in_event => uid = Some(value.to_string()),
+            ("RRULE", value) if in_event => rule = Some(value.to_string()),
+            ("EXDATE", value) if in_event => exdates.extend(parse_instants(params, value)),
+            ("RECURRENCE-ID", value) if in_event => recurrence_id = parse_instants(params, value).pop(),
+            _ => {}
+        }
+    }
+    for (uid, recurrence_id) in overrides {
+        let masters = events.iter_mut().zip(&uids).filter(|(_, event_uid)| event_uid.as_ref() == Some(&uid));
+        for (event, _) in masters {
+            if let Some(recurrence) = &mut event.recurrence {
+                recurrence.exdates.push(recurrence_id);
+            }
+        }
+    }
+    events
+}
+
+/// `in 12m: standup`, for the next timed event of the coming `horizon`.
+pub fn get_next_event_text(events: &[Event], now: DateTime<Utc>, horizon: Duration) -> Option<String> {
+    let event = get_occurrences(events, now, now + horizon).into_iter()
+        .find(|event| !event.all_day && event.start > now)?;
+    let minutes = ((event.start - now).num_seconds() + 59) / 60;
+    let countdown = if minutes < 60 {
+        format!("{}m", minutes)
+    } else {
+        format!("{}h{:02}", minutes / 60, minutes % 60)
+    };
+    Some(format!("in {}: {}", countdown, event.summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        DateTime::from_utc(NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").unwrap(), Utc)
+    }
+
+    fn parse_event(lines: &str) -> Vec<Event> {
+        parse_calendar(&format!("BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\n{}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n", lines))
+    }
+
+    fn get_starts(events: &[Event], from: &str, to: &str) -> Vec<DateTime<Utc>> {
+        get_occurrences(events, utc(from), utc(to)).iter().map(|event| event.start).collect()
+    }
+
+    #[test]
+    fn unfold_continuation_lines() {
+        let lines = unfold("SUMMARY:Quarterly\r\n  planning\r\n\t review\r\nLOCATION:Room 4\r\n");
+        assert_eq!(lines, vec!["SUMMARY:Quarterly planning review", "LOCATION:Room 4"]);
+    }
+
+    #[test]
+    fn split_property_and_params() {
+        assert_eq!(
+            split_property("DTSTART;TZID=Europe/Paris:20190301T090000"),
+            Some(("DTSTART", "TZID=Europe/Paris", "20190301T090000"))
+        );
+        assert_eq!(split_property("SUMMARY:Lunch: pizza"), Some(("SUMMARY", "", "Lunch: pizza")));
+        assert_eq!(
+            split_property("ATTENDEE;CN=\"Doe: Jane\";ROLE=CHAIR:mailto:jane@example.com"),
+            Some(("ATTENDEE", "CN=\"Doe: Jane\";ROLE=CHAIR", "mailto:jane@example.com"))
+        );
+        assert_eq!(split_property("garbage"), None);
+        assert_eq!(get_param("CN=\"Doe: Jane\";ROLE=CHAIR", "cn"), Some("Doe: Jane"));
+    }
+
+    #[test]
+    fn tzid_and_utc_date_times() {
+        let events = parse_event("SUMMARY:Call\r\nDTSTART;TZID=America/New_York:20190301T090000\r\n\
+                                  DTEND;TZID=America/New_York:20190301T093000");
+        assert_eq!(events[0].start, utc("20190301T140000"));
+        assert_eq!(events[0].end, Some(utc("20190301T143000")));
+        assert!(!events[0].all_day);
+        let events = parse_event("SUMMARY:Deploy\r\nDTSTART:20190715T220000Z");
+        assert_eq!(events[0].start, utc("20190715T220000"));
+        assert_eq!(events[0].end, None);
+    }
+
+    #[test]
+    fn all_day_dates() {
+        let events = parse_event("SUMMARY:Holiday\r\nDTSTART;VALUE=DATE:20190415\r\nDTEND;VALUE=DATE:20190417");
+        let date = |day| NaiveDate::from_ymd(2019, 4, day);
+        let midnight = Local.from_local_datetime(&date(15).and_hms(0, 0, 0)).unwrap();
+        assert!(events[0].all_day);
+        assert_eq!(events[0].start, midnight.with_timezone(&Utc));
+        assert!(!events[0].is_on(date(14)));
+        assert!(events[0].is_on(date(15)));
+        assert!(events[0].is_on(date(16)));
+        assert!(!events[0].is_on(date(17)));
+        assert_eq!(get_day_occurrences(&events, date(16), date(16)).len(), 1);
+        assert!(get_day_occurrences(&events, date(17), date(30)).is_empty());
+    }
+
+    #[test]
+    fn weekly_keeps_wall_clock_time() {
+        let events = parse_event("SUMMARY:standup\r\nDTSTART;TZID=Europe/Paris:20190325T093000\r\n\
+                                  DTEND;TZID=Europe/Paris:20190325T094500\r\nRRULE:FREQ=WEEKLY;BYDAY=MO,WE,FR");
+        assert_eq!(
+            get_starts(&events, "20190327T000000", "20190402T000000"),
+            vec![utc("20190327T083000"), utc("20190329T083000"), utc("20190401T073000")]
+        );
+        let occurrences = get_occurrences(&events, utc("20190401T000000"), utc("20190402T000000"));
+        assert_eq!(occurrences[0].end, Some(utc("20190401T074500")));
+        assert_eq!(
+            get_next_event_text(&events, utc("20190610T071800"), Duration::hours(12)),
+            Some(String::from("in 12m: standup"))
+        );
+    }
+
+    #[test]
+    fn daily_interval_and_until() {
+        let events = parse_event("DTSTART:20190301T120000Z\r\nRRULE:FREQ=DAILY;INTERVAL=2;UNTIL=20190307T120000Z");
+        assert_eq!(
+            get_starts(&events, "20190301T000000", "20190401T000000"),
+            vec![utc("20190301T120000"), utc("20190303T120000"), utc("20190305T120000"), utc("20190307T120000")]
+        );
+        // Years of occurrences before the window are skipped
+        let events = parse_event("DTSTART:20100101T080000Z\r\nRRULE:FREQ=DAILY");
+        assert_eq!(
+            get_starts(&events, "20190601T000000", "20190603T000000"),
+            vec![utc("20190601T080000"), utc("20190602T080000")]
+        );
+    }
+
+    #[test]
+    fn count_includes_exdates() {
+        let events = parse_event("DTSTART:20190304T170000Z\r\nRRULE:FREQ=WEEKLY;COUNT=4\r\n\
+                                  EXDATE:20190311T170000Z,20190401T170000Z");
+        assert_eq!(
+            get_starts(&events, "20190301T000000", "20190501T000000"),
+            vec![utc("20190304T170000"), utc("20190318T170000"), utc("20190325T170000")]
+        );
+    }
+
+    #[test]
+    fn monthly_rules() {
+        let events = parse_event("DTSTART:20190125T160000Z\r\nRRULE:FREQ=MONTHLY;BYDAY=-1FR");
+        assert_eq!(
+            get_starts(&events, "20190201T000000", "20190501T000000"),
+            vec![utc("20190222T160000"), utc("20190329T160000"), utc("20190426T160000")]
+        );
+        let events = parse_event("DTSTART:20190131T100000Z\r\nRRULE:FREQ=MONTHLY;COUNT=3");
+        assert_eq!(
+            get_starts(&events, "20190101T000000", "20200101T000000"),
+            vec![utc("20190131T100000"), utc("20190331T100000"), utc("20190531T100000")]
+        );
+        let events = parse_event("DTSTART:20190301T100000Z\r\nRRULE:FREQ=MONTHLY;BYMONTHDAY=1,15");
+        assert_eq!(get_starts(&events, "20190101T000000", "20200101T000000"), vec![utc("20190301T100000")]);
+    }
+
+    #[test]
+    fn recurrence_id_moves_an_occurrence() {
+        let events = parse_calendar("BEGIN:VCALENDAR\r\n\
+                                     BEGIN:VEVENT\r\nUID:review\r\nSUMMARY:Review\r\n\
+                                     DTSTART:20190304T150000Z\r\nRRULE:FREQ=WEEKLY\r\nEND:VEVENT\r\n\
+                                     BEGIN:VEVENT\r\nUID:review\r\nSUMMARY:Review (moved)\r\n\
+                                     RECURRENCE-ID:20190311T150000Z\r\nDTSTART:20190312T100000Z\r\nEND:VEVENT\r\n\
+                                     END:VCALENDAR\r\n");
+        let occurrences = get_occurrences(&events, utc("20190310T000000"), utc("20190320T000000"));
+        let summaries: Vec<(&str, DateTime<Utc>)> = occurrences.iter()
+            .map(|event| (event.summary.as_str(), event.start))
+            .collect();
+        assert_eq!(summaries, vec![("Review (moved)", utc("20190312T100000")), ("Review", utc("20190318T150000"))]);
+    }
+
+    #[test]
+    fn alarm_properties_ignored() {
+        let events = parse_event("SUMMARY:Dentist\r\nDTSTART:20190402T080000Z\r\nBEGIN:VALARM\r\n\
+                                  ACTION:DISPLAY\r\nSUMMARY:Reminder\r\nDESCRIPTION:Leave now\r\n\
+                                  TRIGGER:-PT15M\r\nEND:VALARM\r\nDTEND:20190402T090000Z");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].summary, "Dentist");
+        assert_eq!(events[0].end, Some(utc("20190402T090000")));
+        // A task is not an event, even with a start
+        let todo = parse_calendar("BEGIN:VCALENDAR\r\nBEGIN:VTODO\r\nSUMMARY:Taxes\r\n\
+                                   DTSTART:20190415T000000Z\r\nEND:VTODO\r\nEND:VCALENDAR\r\n");
+        assert!(todo.is_empty());
+    }
+
+    #[test]
+    fn duration_instead_of_end() {
+        let events = parse_event("SUMMARY:Review\r\nDTSTART:20190402T080000Z\r\nDURATION:PT1H30M");
+        assert_eq!(events[0].end, Some(utc("20190402T093000")));
+        let events = parse_event("SUMMARY:Trip\r\nDTSTART;VALUE=DATE:20190415\r\nDURATION:P1W2D");
+        assert!(events[0].is_on(NaiveDate::from_ymd(2019, 4, 23)));
+        assert!(!events[0].is_on(NaiveDate::from_ymd(2019, 4, 24)));
+        assert_eq!(parse_duration("-PT15M"), Some(Duration::minutes(-15)));
+        assert_eq!(parse_duration("P1H"), None);
+        assert_eq!(parse_duration("PT"), None);
+        assert_eq!(parse_duration("PT5"), None);
+    }
+}
@@ -0,0 +1,18 @@
+//! Paths to the sysfs and procfs trees captured under `fixtures/`, shared by
+//! the tests of the modules reading them.
+
+use std::path::{Path, PathBuf};
+
+fn fixtures() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+/// The sysfs root captured from `machine`.
+pub fn sysfs(machine: &str) -> PathBuf {
+    fixtures().join("sysfs").join(machine)
+}
+
+/// The procfs root captured from `machine`.
+pub fn procfs(machine: &str) -> PathBuf {
+    fixtures().join("procfs").join(machine)
+}
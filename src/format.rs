@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+const ELLIPSIS: char = '…';
+
+/// Replaces every `{key}` placeholder of `template` by the value `lookup`
+/// returns for `key`, or by nothing when it returns `None`. A placeholder
+/// can be truncated to a number of characters with `{key:20}`, and `{{` /
+/// `}}` produce literal braces.
+pub fn format<F>(template: &str, lookup: F) -> String
+    where F: Fn(&str) -> Option<String> {
+    let mut output = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(my_char) = chars.next() {
+        match my_char {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                output.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                output.push('}');
+            },
+            '{' => {
+                let mut placeholder = String::new();
+                let mut closed = false;
+                for placeholder_char in &mut chars {
+                    if placeholder_char == '}' {
+                        closed = true;
+                        break;
+                    }
+                    placeholder.push(placeholder_char);
+                }
+                if !closed {
+                    output.push('{');
+                    output.push_str(&placeholder);
+                    continue;
+                }
+                let (key, width) = split_placeholder(&placeholder);
+                if let Some(value) = lookup(key) {
+                    match width {
+                        Some(width) => output.push_str(&truncate(&value, width)),
+                        None => output.push_str(&value)
+                    }
+                }
+            },
+            _ => output.push(my_char)
+        }
+    }
+    output
+}
+
+/// Splits `key:20` into the key and its maximum width. Keys can contain
+/// colons themselves (`xesam:genre`), so only a numeric suffix is a width.
+fn split_placeholder(placeholder: &str) -> (&str, Option<usize>) {
+    if let Some(index) = placeholder.rfind(':') {
+        if let Ok(width) = placeholder[index + 1..].parse::<usize>() {
+            return (&placeholder[..index], Some(width));
+        }
+    }
+    (placeholder, None)
+}
+
+/// Cuts `value` to at most `width` characters, the last one being an
+/// ellipsis when something was removed.
+pub fn truncate(value: &str, width: usize) -> String {
+    if value.chars().count() <= width {
+        return value.to_string();
+    }
+    if width == 0 {
+        return String::new();
+    }
+    let mut truncated: String = value.chars().take(width - 1).collect();
+    truncated.push(ELLIPSIS);
+    truncated
+}
+
+/// Formats a size in binary units, e.g. `1.2M`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration as `m:ss`, or `h:mm:ss` past an hour.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
@@ -1,10 +1,18 @@
+extern crate alsa;
 extern crate cairo;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate clap;
+extern crate dbus;
 extern crate gdk;
+extern crate gdk_pixbuf;
 extern crate gio;
 extern crate glib;
+extern crate glib_sys;
 extern crate gtk;
+extern crate libc;
+extern crate libpulse_binding;
+extern crate libpulse_glib_binding;
 extern crate mpris;
 extern crate pango;
 extern crate x11_get_windows;
@@ -12,25 +20,99 @@ extern crate x11_get_windows;
 use clap::{App, Arg};
 
 use gio::prelude::*;
+use std::path::PathBuf;
+use std::str::FromStr;
+use chrono_tz::Tz;
+use crate::battery::{Backend, BatterySettings};
+use crate::clock::{is_valid_format, ClockSettings};
+use crate::cpu::{CpuMode, CpuSettings};
+use crate::disk::{DiskSettings, Mount};
+use crate::marquee::MarqueeSettings;
+use crate::memory::MemorySettings;
+use crate::network::NetworkSettings;
+use crate::player::PlayerSettings;
+use crate::temperature::TemperatureSettings;
+use crate::timer::{TimerMode, TimerSettings};
+use crate::volume::{VolumeBackend, VolumeSettings};
 use crate::window::build_ui;
 
 mod battery;
 mod bspwm;
 mod clock;
+mod command;
+mod cover;
+mod cpu;
+mod disk;
+mod events;
+#[cfg(test)]
+mod fixtures;
+mod format;
+mod marquee;
+mod memory;
+mod network;
+mod notify;
 mod paint;
 mod player;
+mod pulse;
+mod temperature;
+mod timer;
+mod upower;
+mod volume;
 mod window;
 mod x11_title;
 
 const REFRESH_INTERVAL: u32 = 100;
 
 pub struct Settings {
-    debug: bool
+    debug: bool,
+    /// Where sysfs and procfs are read from, to run against captured
+    /// fixtures instead of the real hardware.
+    sysfs_root: PathBuf,
+    procfs_root: PathBuf,
+    battery: BatterySettings,
+    clock: ClockSettings,
+    cpu: CpuSettings,
+    disk: DiskSettings,
+    marquee: MarqueeSettings,
+    memory: MemorySettings,
+    network: NetworkSettings,
+    player: PlayerSettings,
+    temperature: TemperatureSettings,
+    timer: TimerSettings,
+    volume: VolumeSettings
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct Null;
 
+fn validate_clock_format(format: String) -> Result<(), String> {
+    if is_valid_format(&format) {
+        Ok(())
+    } else {
+        Err(format!("invalid strftime format \"{}\"", format))
+    }
+}
+
+fn validate_number<T: FromStr>(value: String) -> Result<(), String> {
+    value.parse::<T>().map(|_| ()).map_err(|_| format!("invalid number \"{}\"", value))
+}
+
+fn validate_percent(value: String) -> Result<(), String> {
+    match value.parse::<u8>() {
+        Ok(percent) if percent <= 100 => Ok(()),
+        _ => Err(format!("invalid percentage \"{}\"", value))
+    }
+}
+
+/// `<percent>:<command>`, e.g. `3:systemctl suspend`.
+fn validate_battery_action(value: String) -> Result<(), String> {
+    let mut tokens = value.splitn(2, ':');
+    match (tokens.next().map(|percent| validate_percent(percent.trim().to_string())), tokens.next()) {
+        (Some(Ok(())), Some(command)) if !command.trim().is_empty() => Ok(()),
+        _ => Err(format!("invalid action \"{}\", expected <percent>:<command>", value))
+    }
+}
+
 fn args_to_settings() -> Settings {
     let matches = App::new("malabar")
         .version("alpha")
@@ -40,9 +122,421 @@ fn args_to_settings() -> Settings {
              .short("d")
              .index(1))
         .help("Turn debugging information on")
+        .arg(Arg::with_name("sysfs-root")
+             .long("sysfs-root")
+             .takes_value(true)
+             .env("MALABAR_SYSFS_ROOT")
+             .default_value("/sys")
+             .help("Directory read instead of /sys, e.g. fixtures/sysfs/thinkpad-t480"))
+        .arg(Arg::with_name("procfs-root")
+             .long("procfs-root")
+             .takes_value(true)
+             .env("MALABAR_PROCFS_ROOT")
+             .default_value("/proc")
+             .help("Directory read instead of /proc"))
+        .arg(Arg::with_name("player-format")
+             .long("player-format")
+             .takes_value(true)
+             .help("Player text, e.g. \"{artist} - {title:30} [{album}]\""))
+        .arg(Arg::with_name("player-progress-bar")
+             .long("player-progress-bar")
+             .help("Show a progress bar under the player text, click on it to seek"))
+        .arg(Arg::with_name("player-time")
+             .long("player-time")
+             .help("Show the elapsed and remaining time of the current track"))
+        .arg(Arg::with_name("player-art")
+             .long("player-art")
+             .help("Show the cover of the current track in the bar"))
+        .arg(Arg::with_name("player-popup")
+             .long("player-popup")
+             .help("Show cover, artist, album, title and progress when hovering the player"))
+        .arg(Arg::with_name("player-shuffle")
+             .long("player-shuffle")
+             .help("Show a button toggling the player shuffle"))
+        .arg(Arg::with_name("player-loop")
+             .long("player-loop")
+             .help("Show a button cycling through the player loop status"))
+        .arg(Arg::with_name("battery-backend")
+             .long("battery-backend")
+             .takes_value(true)
+             .possible_values(&["sysfs", "upower"])
+             .help("Read the batteries from sysfs or UPower"))
+        .arg(Arg::with_name("battery-upower-session")
+             .long("battery-upower-session")
+             .help("Look for UPower on the session bus instead of the system one"))
+        .arg(Arg::with_name("battery-separate")
+             .long("battery-separate")
+             .help("Show each battery separately instead of their aggregate"))
+        .arg(Arg::with_name("battery-format")
+             .long("battery-format")
+             .takes_value(true)
+             .help("Battery text, e.g. \"{icon}{percent}% ({time})\""))
+        .arg(Arg::with_name("battery-warning")
+             .long("battery-warning")
+             .takes_value(true)
+             .validator(validate_percent)
+             .help("Battery percentage turning the module yellow"))
+        .arg(Arg::with_name("battery-critical")
+             .long("battery-critical")
+             .takes_value(true)
+             .validator(validate_percent)
+             .help("Battery percentage turning the module red and blinking"))
+        .arg(Arg::with_name("battery-notify")
+             .long("battery-notify")
+             .help("Send a desktop notification at the warning and critical percentages"))
+        .arg(Arg::with_name("battery-action")
+             .long("battery-action")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .validator(validate_battery_action)
+             .help("Command run once when discharging down to a percentage, e.g. \"3:systemctl suspend\""))
+        .arg(Arg::with_name("clock-format")
+             .long("clock-format")
+             .takes_value(true)
+             .validator(validate_clock_format)
+             .help("Clock text in strftime format, e.g. \"%a %d %H:%M\""))
+        .arg(Arg::with_name("clock-alt-format")
+             .long("clock-alt-format")
+             .takes_value(true)
+             .validator(validate_clock_format)
+             .help("Clock text shown after a right click, e.g. \"%A %d %B %Y\""))
+        .arg(Arg::with_name("clock-week-numbers")
+             .long("clock-week-numbers")
+             .help("Show ISO week numbers in the calendar"))
+        .arg(Arg::with_name("clock-timezone")
+             .long("clock-timezone")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .validator(|timezone| timezone.parse::<Tz>().map(|_| ()))
+             .help("Additional clock in an IANA timezone, e.g. \"America/New_York\""))
+        .arg(Arg::with_name("clock-calendar")
+             .long("clock-calendar")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .help("iCalendar file or vdir directory to show the next event from"))
+        .arg(Arg::with_name("volume-backend")
+             .long("volume-backend")
+             .takes_value(true)
+             .possible_values(&["alsa", "pulse"])
+             .help("Read the volume from an ALSA mixer or from PulseAudio / pipewire-pulse"))
+        .arg(Arg::with_name("volume-card")
+             .long("volume-card")
+             .takes_value(true)
+             .help("ALSA card of the volume module, e.g. \"hw:1\""))
+        .arg(Arg::with_name("volume-control")
+             .long("volume-control")
+             .takes_value(true)
+             .help("ALSA mixer control of the volume module, e.g. \"PCM\""))
+        .arg(Arg::with_name("volume-step")
+             .long("volume-step")
+             .takes_value(true)
+             .validator(validate_percent)
+             .help("Volume percentage changed by each scroll step"))
+        .arg(Arg::with_name("volume-format")
+             .long("volume-format")
+             .takes_value(true)
+             .help("Volume text, e.g. \"{icon}{percent}%\""))
+        .arg(Arg::with_name("cpu")
+             .long("cpu")
+             .help("Show the CPU usage"))
+        .arg(Arg::with_name("cpu-mode")
+             .long("cpu-mode")
+             .takes_value(true)
+             .possible_values(&["text", "bars", "sparkline"])
+             .help("Show the CPU usage as text, as one bar per core or with a sparkline"))
+        .arg(Arg::with_name("cpu-format")
+             .long("cpu-format")
+             .takes_value(true)
+             .help("CPU text, e.g. \"{icon}{usage}% {load1}\""))
+        .arg(Arg::with_name("cpu-warning")
+             .long("cpu-warning")
+             .takes_value(true)
+             .validator(validate_percent)
+             .help("CPU usage percentage turning the module yellow"))
+        .arg(Arg::with_name("cpu-critical")
+             .long("cpu-critical")
+             .takes_value(true)
+             .validator(validate_percent)
+             .help("CPU usage percentage turning the module red"))
+        .arg(Arg::with_name("disk")
+             .long("disk")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .help("Mount point to show the disk usage of, with an optional format, e.g. \"/home:{free} free\", a trailing colon ends a mount point containing colons"))
+        .arg(Arg::with_name("disk-format")
+             .long("disk-format")
+             .takes_value(true)
+             .help("Disk text, e.g. \"{icon}{path} {percent}%\""))
+        .arg(Arg::with_name("disk-warning")
+             .long("disk-warning")
+             .takes_value(true)
+             .validator(validate_percent)
+             .help("Used disk percentage turning a mount point yellow"))
+        .arg(Arg::with_name("disk-critical")
+             .long("disk-critical")
+             .takes_value(true)
+             .validator(validate_percent)
+             .help("Used disk percentage turning a mount point red"))
+        .arg(Arg::with_name("disk-file-manager")
+             .long("disk-file-manager")
+             .takes_value(true)
+             .help("Command opening a clicked mount point, e.g. \"thunar\""))
+        .arg(Arg::with_name("memory")
+             .long("memory")
+             .help("Show the memory usage"))
+        .arg(Arg::with_name("memory-format")
+             .long("memory-format")
+             .takes_value(true)
+             .help("Memory text, e.g. \"{icon}{percent}% {swap_used}\""))
+        .arg(Arg::with_name("memory-warning")
+             .long("memory-warning")
+             .takes_value(true)
+             .validator(validate_percent)
+             .help("Used memory percentage turning the module yellow"))
+        .arg(Arg::with_name("memory-critical")
+             .long("memory-critical")
+             .takes_value(true)
+             .validator(validate_percent)
+             .help("Used memory percentage turning the module red"))
+        .arg(Arg::with_name("memory-processes")
+             .long("memory-processes")
+             .takes_value(true)
+             .validator(validate_number::<usize>)
+             .help("Processes using the most memory listed in the tooltip, 0 for none"))
+        .arg(Arg::with_name("network")
+             .long("network")
+             .help("Show the network connection"))
+        .arg(Arg::with_name("network-interface")
+             .long("network-interface")
+             .takes_value(true)
+             .help("Network interface to show instead of the one of the default route"))
+        .arg(Arg::with_name("network-format")
+             .long("network-format")
+             .takes_value(true)
+             .help("Network text, e.g. \"{icon}{essid} {signal}% {down} {up}\""))
+        .arg(Arg::with_name("temperature")
+             .long("temperature")
+             .help("Show the temperature of the CPU or of the configured sensors"))
+        .arg(Arg::with_name("temperature-sensor")
+             .long("temperature-sensor")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .help("Sensor as its hwmon chip or thermal zone type and label, e.g. \"coretemp:Package id 0\""))
+        .arg(Arg::with_name("temperature-format")
+             .long("temperature-format")
+             .takes_value(true)
+             .help("Temperature text, e.g. \"{icon}{label} {temp}°C\""))
+        .arg(Arg::with_name("temperature-warning")
+             .long("temperature-warning")
+             .takes_value(true)
+             .validator(validate_number::<u8>)
+             .help("Degrees turning a sensor yellow"))
+        .arg(Arg::with_name("temperature-critical")
+             .long("temperature-critical")
+             .takes_value(true)
+             .validator(validate_number::<u8>)
+             .help("Degrees turning a sensor red instead of its critical trip point"))
+        .arg(Arg::with_name("temperature-fans")
+             .long("temperature-fans")
+             .help("Show the speed of the spinning fans"))
+        .arg(Arg::with_name("timer")
+             .long("timer")
+             .help("Show a countdown, stopwatch and pomodoro timer next to the clock"))
+        .arg(Arg::with_name("timer-mode")
+             .long("timer-mode")
+             .takes_value(true)
+             .possible_values(&["countdown", "stopwatch", "pomodoro"])
+             .help("Timer mode until another one is picked with a middle click"))
+        .arg(Arg::with_name("timer-command")
+             .long("timer-command")
+             .takes_value(true)
+             .help("Command run when a countdown or a pomodoro phase ends"))
+        .arg(Arg::with_name("marquee")
+             .long("marquee")
+             .help("Scroll long player and window titles instead of showing them whole"))
+        .arg(Arg::with_name("marquee-width")
+             .long("marquee-width")
+             .takes_value(true)
+             .validator(validate_number::<usize>)
+             .help("Visible characters of scrolling titles"))
+        .arg(Arg::with_name("marquee-speed")
+             .long("marquee-speed")
+             .takes_value(true)
+             .validator(validate_number::<u32>)
+             .help("Milliseconds between two steps of scrolling titles"))
+        .arg(Arg::with_name("marquee-pause")
+             .long("marquee-pause")
+             .takes_value(true)
+             .validator(validate_number::<u32>)
+             .help("Milliseconds scrolling titles stay at each end"))
         .get_matches();
-    let mut settings = Settings { debug: false };
+    let mut settings = Settings {
+        debug: false,
+        sysfs_root: PathBuf::from("/sys"),
+        procfs_root: PathBuf::from("/proc"),
+        battery: BatterySettings::default(),
+        clock: ClockSettings::default(),
+        cpu: CpuSettings::default(),
+        disk: DiskSettings::default(),
+        marquee: MarqueeSettings::default(),
+        memory: MemorySettings::default(),
+        network: NetworkSettings::default(),
+        player: PlayerSettings::default(),
+        temperature: TemperatureSettings::default(),
+        timer: TimerSettings::default(),
+        volume: VolumeSettings::default()
+    };
     if matches.is_present("debug") { settings.debug = true; };
+    settings.sysfs_root = PathBuf::from(matches.value_of("sysfs-root").unwrap_or("/sys"));
+    settings.procfs_root = PathBuf::from(matches.value_of("procfs-root").unwrap_or("/proc"));
+    if let Some(format) = matches.value_of("player-format") {
+        settings.player.format = format.to_string();
+    }
+    settings.player.progress_bar = matches.is_present("player-progress-bar");
+    settings.player.time = matches.is_present("player-time");
+    settings.player.art = matches.is_present("player-art");
+    settings.player.popup = matches.is_present("player-popup");
+    settings.player.shuffle = matches.is_present("player-shuffle");
+    settings.player.loop_status = matches.is_present("player-loop");
+    if matches.value_of("battery-backend") == Some("upower") {
+        settings.battery.backend = Backend::UPower;
+    }
+    settings.battery.upower_session = matches.is_present("battery-upower-session");
+    settings.battery.separate = matches.is_present("battery-separate");
+    if let Some(format) = matches.value_of("battery-format") {
+        settings.battery.format = format.to_string();
+    }
+    if let Some(warning) = matches.value_of("battery-warning").and_then(|warning| warning.parse().ok()) {
+        settings.battery.warning = warning;
+    }
+    if let Some(critical) = matches.value_of("battery-critical").and_then(|critical| critical.parse().ok()) {
+        settings.battery.critical = critical;
+    }
+    if settings.battery.critical >= settings.battery.warning {
+        clap::Error::with_description(
+            &format!("--battery-critical ({}) must be below --battery-warning ({})",
+                     settings.battery.critical, settings.battery.warning),
+            clap::ErrorKind::ArgumentConflict
+        ).exit();
+    }
+    settings.battery.notify = matches.is_present("battery-notify");
+    if let Some(actions) = matches.values_of("battery-action") {
+        settings.battery.actions = actions.filter_map(|action| {
+            let mut tokens = action.splitn(2, ':');
+            match (tokens.next().and_then(|percent| percent.trim().parse().ok()), tokens.next()) {
+                (Some(percent), Some(command)) => Some((percent, command.to_string())),
+                _ => None
+            }
+        }).collect();
+    }
+    if let Some(format) = matches.value_of("clock-format") {
+        settings.clock.format = format.to_string();
+    }
+    settings.clock.alt_format = matches.value_of("clock-alt-format").map(String::from);
+    settings.clock.week_numbers = matches.is_present("clock-week-numbers");
+    if let Some(timezones) = matches.values_of("clock-timezone") {
+        settings.clock.timezones = timezones.filter_map(|timezone| timezone.parse().ok()).collect();
+    }
+    if let Some(calendars) = matches.values_of("clock-calendar") {
+        settings.clock.calendars = calendars.map(PathBuf::from).collect();
+    }
+    if matches.value_of("volume-backend") == Some("pulse") {
+        settings.volume.backend = VolumeBackend::Pulse;
+    }
+    if let Some(card) = matches.value_of("volume-card") {
+        settings.volume.card = card.to_string();
+    }
+    if let Some(control) = matches.value_of("volume-control") {
+        settings.volume.control = control.to_string();
+    }
+    if let Some(step) = matches.value_of("volume-step").and_then(|step| step.parse().ok()) {
+        settings.volume.step = step;
+    }
+    if let Some(format) = matches.value_of("volume-format") {
+        settings.volume.format = format.to_string();
+    }
+    settings.cpu.enabled = matches.is_present("cpu");
+    settings.cpu.mode = match matches.value_of("cpu-mode") {
+        Some("bars") => CpuMode::Bars,
+        Some("sparkline") => CpuMode::Sparkline,
+        _ => CpuMode::Text
+    };
+    if let Some(format) = matches.value_of("cpu-format") {
+        settings.cpu.format = format.to_string();
+    }
+    if let Some(warning) = matches.value_of("cpu-warning").and_then(|warning| warning.parse().ok()) {
+        settings.cpu.warning = warning;
+    }
+    if let Some(critical) = matches.value_of("cpu-critical").and_then(|critical| critical.parse().ok()) {
+        settings.cpu.critical = critical;
+    }
+    if let Some(mounts) = matches.values_of("disk") {
+        settings.disk.mounts = mounts.map(Mount::parse).collect();
+    }
+    if let Some(format) = matches.value_of("disk-format") {
+        settings.disk.format = format.to_string();
+    }
+    if let Some(warning) = matches.value_of("disk-warning").and_then(|warning| warning.parse().ok()) {
+        settings.disk.warning = warning;
+    }
+    if let Some(critical) = matches.value_of("disk-critical").and_then(|critical| critical.parse().ok()) {
+        settings.disk.critical = critical;
+    }
+    if let Some(file_manager) = matches.value_of("disk-file-manager") {
+        settings.disk.file_manager = file_manager.to_string();
+    }
+    settings.memory.enabled = matches.is_present("memory");
+    if let Some(format) = matches.value_of("memory-format") {
+        settings.memory.format = format.to_string();
+    }
+    if let Some(warning) = matches.value_of("memory-warning").and_then(|warning| warning.parse().ok()) {
+        settings.memory.warning = warning;
+    }
+    if let Some(critical) = matches.value_of("memory-critical").and_then(|critical| critical.parse().ok()) {
+        settings.memory.critical = critical;
+    }
+    if let Some(processes) = matches.value_of("memory-processes").and_then(|processes| processes.parse().ok()) {
+        settings.memory.processes = processes;
+    }
+    settings.network.enabled = matches.is_present("network");
+    settings.network.interface = matches.value_of("network-interface").map(String::from);
+    if let Some(format) = matches.value_of("network-format") {
+        settings.network.format = format.to_string();
+    }
+    settings.temperature.enabled = matches.is_present("temperature");
+    if let Some(sensors) = matches.values_of("temperature-sensor") {
+        settings.temperature.sensors = sensors.map(String::from).collect();
+    }
+    if let Some(format) = matches.value_of("temperature-format") {
+        settings.temperature.format = format.to_string();
+    }
+    if let Some(warning) = matches.value_of("temperature-warning").and_then(|warning| warning.parse().ok()) {
+        settings.temperature.warning = warning;
+    }
+    settings.temperature.critical = matches.value_of("temperature-critical").and_then(|critical| critical.parse().ok());
+    settings.temperature.fans = matches.is_present("temperature-fans");
+    settings.timer.enabled = matches.is_present("timer");
+    settings.timer.mode = match matches.value_of("timer-mode") {
+        Some("stopwatch") => TimerMode::Stopwatch,
+        Some("pomodoro") => TimerMode::Pomodoro,
+        _ => TimerMode::Countdown
+    };
+    settings.timer.command = matches.value_of("timer-command").map(String::from);
+    settings.marquee.enabled = matches.is_present("marquee");
+    if let Some(width) = matches.value_of("marquee-width").and_then(|width| width.parse().ok()) {
+        settings.marquee.width = width;
+    }
+    if let Some(speed) = matches.value_of("marquee-speed").and_then(|speed| speed.parse().ok()) {
+        settings.marquee.speed = speed;
+    }
+    if let Some(pause) = matches.value_of("marquee-pause").and_then(|pause| pause.parse().ok()) {
+        settings.marquee.pause = pause;
+    }
     settings
 }
 
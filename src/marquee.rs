@@ -0,0 +1,203 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use glib::{Continue, SourceId};
+use gtk::{
+    timeout_add,
+    ContainerExt,
+    EventBox,
+    Inhibit,
+    Label,
+    LabelExt,
+    WidgetExt
+};
+
+#[derive(Clone)]
+pub struct MarqueeSettings {
+    pub enabled: bool,
+    /// Visible characters, longer texts scroll.
+    pub width: usize,
+    /// Milliseconds between two one-character steps.
+    pub speed: u32,
+    /// Milliseconds spent at each end before scrolling back.
+    pub pause: u32
+}
+
+impl Default for MarqueeSettings {
+    fn default() -> MarqueeSettings {
+        MarqueeSettings {
+            enabled: false,
+            width: 30,
+            speed: 250,
+            pause: 1500
+        }
+    }
+}
+
+struct MarqueeState {
+    text: Vec<char>,
+    offset: usize,
+    forward: bool,
+    paused_until: Option<Instant>,
+    hovered: bool,
+    timer: Option<SourceId>
+}
+
+/// Shared by the marquee and its timer and signal handlers, which only
+/// keep a weak reference so the label does not keep it alive.
+struct MarqueeInner {
+    label: Label,
+    settings: MarqueeSettings,
+    state: RefCell<MarqueeState>
+}
+
+/// Label scrolling back and forth through texts longer than its width. The
+/// timer only runs while the text overflows and the label is mapped, and the
+/// scrolling stops while the pointer is over it.
+#[derive(Clone)]
+pub struct Marquee {
+    event_box: EventBox,
+    inner: Rc<MarqueeInner>
+}
+
+impl Marquee {
+    pub fn new(label: &Label, settings: &MarqueeSettings) -> Marquee {
+        let event_box = EventBox::new();
+        event_box.add(label);
+        let marquee = Marquee {
+            event_box,
+            inner: Rc::new(MarqueeInner {
+                label: label.clone(),
+                settings: settings.clone(),
+                state: RefCell::new(MarqueeState {
+                    text: Vec::new(),
+                    offset: 0,
+                    forward: true,
+                    paused_until: None,
+                    hovered: false,
+                    timer: None
+                })
+            })
+        };
+        if settings.enabled {
+            label.set_width_chars(settings.width as i32);
+            label.set_xalign(0.0);
+            marquee.event_box.add_events((gdk::EventMask::ENTER_NOTIFY_MASK
+                                          | gdk::EventMask::LEAVE_NOTIFY_MASK).bits() as i32);
+            let inner = Rc::downgrade(&marquee.inner);
+            marquee.event_box.connect_enter_notify_event(move |_, _| {
+                if let Some(inner) = inner.upgrade() {
+                    inner.state.borrow_mut().hovered = true;
+                }
+                Inhibit(false)
+            });
+            let inner = Rc::downgrade(&marquee.inner);
+            marquee.event_box.connect_leave_notify_event(move |_, _| {
+                if let Some(inner) = inner.upgrade() {
+                    inner.state.borrow_mut().hovered = false;
+                }
+                Inhibit(false)
+            });
+            let inner = Rc::downgrade(&marquee.inner);
+            label.connect_map(move |_| {
+                if let Some(inner) = inner.upgrade() {
+                    MarqueeInner::update_timer(&inner);
+                }
+            });
+            let inner = Rc::downgrade(&marquee.inner);
+            label.connect_unmap(move |_| {
+                if let Some(inner) = inner.upgrade() {
+                    MarqueeInner::update_timer(&inner);
+                }
+            });
+        }
+        marquee
+    }
+
+    pub fn widget(&self) -> &EventBox {
+        &self.event_box
+    }
+
+    /// A text of the same length keeps the scrolling where it is, so that
+    /// titles with a changing `{position}` still scroll.
+    pub fn set_text(&self, text: &str) {
+        let inner = &self.inner;
+        if !inner.settings.enabled {
+            inner.label.set_text(text);
+            return;
+        }
+        {
+            let mut state = inner.state.borrow_mut();
+            let text: Vec<char> = text.chars().collect();
+            if state.text == text {
+                return;
+            }
+            if state.text.len() != text.len() {
+                state.offset = 0;
+                state.forward = true;
+                state.paused_until = Some(Instant::now() + Duration::from_millis(u64::from(inner.settings.pause)));
+            }
+            state.text = text;
+        }
+        inner.render();
+        MarqueeInner::update_timer(inner);
+    }
+}
+
+impl MarqueeInner {
+    fn overflows(&self) -> bool {
+        self.state.borrow().text.len() > self.settings.width
+    }
+
+    fn update_timer(inner: &Rc<MarqueeInner>) {
+        let should_run = inner.overflows() && inner.label.get_mapped();
+        let mut state = inner.state.borrow_mut();
+        if should_run && state.timer.is_none() {
+            let weak_inner = Rc::downgrade(inner);
+            state.timer = Some(timeout_add(inner.settings.speed, move || match weak_inner.upgrade() {
+                Some(inner) => inner.tick(),
+                None => Continue(false)
+            }));
+        } else if !should_run {
+            if let Some(timer) = state.timer.take() {
+                glib::source_remove(timer);
+            }
+        }
+    }
+
+    fn tick(&self) -> Continue {
+        {
+            let mut state = self.state.borrow_mut();
+            if state.hovered {
+                return Continue(true);
+            }
+            if let Some(paused_until) = state.paused_until {
+                if Instant::now() < paused_until {
+                    return Continue(true);
+                }
+                state.paused_until = None;
+            }
+            let max_offset = state.text.len().saturating_sub(self.settings.width);
+            if state.forward && state.offset < max_offset {
+                state.offset += 1;
+            } else if !state.forward && state.offset > 0 {
+                state.offset -= 1;
+            }
+            if state.offset == max_offset || state.offset == 0 {
+                state.forward = state.offset == 0;
+                state.paused_until = Some(Instant::now() + Duration::from_millis(u64::from(self.settings.pause)));
+            }
+        }
+        self.render();
+        Continue(true)
+    }
+
+    fn render(&self) {
+        let state = self.state.borrow();
+        let visible: String = state.text.iter()
+            .skip(state.offset)
+            .take(self.settings.width)
+            .collect();
+        self.label.set_text(&visible);
+    }
+}
@@ -0,0 +1,238 @@
+use gtk::{
+    timeout_add,
+    Continue,
+    Label,
+    LabelExt,
+    TooltipExt,
+    WidgetExt
+};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use crate::format::{format, format_bytes};
+use crate::paint::{get_threshold_color, set_label_color};
+use crate::Settings;
+
+const REFRESH_RATE: u32 = 1000;
+const DEFAULT_FORMAT: &str = "{icon}{used}/{total}";
+const ICON: &str = "\u{f538} ";
+
+#[derive(Clone)]
+pub struct MemorySettings {
+    pub enabled: bool,
+    /// Placeholders are `{icon}`, `{used}`, `{available}`, `{total}`,
+    /// `{percent}`, `{swap_used}`, `{swap_total}` and `{swap_percent}`.
+    pub format: String,
+    /// Used memory percentages turning the module yellow, then red.
+    pub warning: u8,
+    pub critical: u8,
+    /// Processes listed in the tooltip.
+    pub processes: usize
+}
+
+impl Default for MemorySettings {
+    fn default() -> MemorySettings {
+        MemorySettings {
+            enabled: false,
+            format: String::from(DEFAULT_FORMAT),
+            warning: 80,
+            critical: 90,
+            processes: 5
+        }
+    }
+}
+
+/// Sizes in bytes.
+struct MemoryInfo {
+    total: u64,
+    available: u64,
+    swap_total: u64,
+    swap_free: u64
+}
+
+pub struct MemoryWidget {
+    procfs_root: PathBuf,
+    format: String,
+    warning: f64,
+    critical: f64,
+    text: String,
+    color: Option<(u8, u8, u8)>
+}
+
+fn get_percent(used: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { used as f64 * 100.0 / total as f64 }
+}
+
+/// `/proc/meminfo` and `/proc/<pid>/status` lines are `Key:   1234 kB`.
+fn parse_kilobytes(value: &str) -> Option<u64> {
+    value.split_whitespace().next()?.parse::<u64>().ok().map(|kilobytes| kilobytes * 1024)
+}
+
+fn get_memory_info(procfs_root: &Path) -> Option<MemoryInfo> {
+    let content = fs::read_to_string(procfs_root.join("meminfo")).ok()?;
+    let values: HashMap<&str, u64> = content.lines().filter_map(|line| {
+        let mut tokens = line.splitn(2, ':');
+        Some((tokens.next()?, parse_kilobytes(tokens.next()?)?))
+    }).collect();
+    let total = *values.get("MemTotal")?;
+    // Before Linux 3.14, estimated the way free(1) used to
+    let available = values.get("MemAvailable").cloned().unwrap_or_else(|| {
+        ["MemFree", "Buffers", "Cached"].iter().filter_map(|key| values.get(key)).sum()
+    });
+    Some(MemoryInfo {
+        total,
+        available: available.min(total),
+        swap_total: values.get("SwapTotal").cloned().unwrap_or(0),
+        swap_free: values.get("SwapFree").cloned().unwrap_or(0)
+    })
+}
+
+/// Name and resident size of the processes using the most memory. Kernel
+/// threads have no `VmRSS` and are left out.
+fn get_top_processes(procfs_root: &Path, count: usize) -> Vec<(String, u64)> {
+    let entries = match fs::read_dir(procfs_root) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    let mut processes: Vec<(String, u64)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().chars().all(|c| c.is_ascii_digit()))
+        .filter_map(|entry| {
+            // Processes can exit while being listed
+            let status = fs::read_to_string(entry.path().join("status")).ok()?;
+            let (mut name, mut rss) = (None, None);
+            for line in status.lines() {
+                let mut tokens = line.splitn(2, ':');
+                match (tokens.next(), tokens.next()) {
+                    (Some("Name"), Some(value)) => name = Some(value.trim().to_string()),
+                    (Some("VmRSS"), Some(value)) => rss = parse_kilobytes(value),
+                    _ => {}
+                }
+            }
+            Some((name?, rss?))
+        })
+        .collect();
+    processes.sort_by(|(_, a), (_, b)| b.cmp(a));
+    processes.truncate(count);
+    processes
+}
+
+impl MemoryWidget {
+    fn update(&mut self, label: &Label, force_refresh: bool) {
+        let (text, percent) = match get_memory_info(&self.procfs_root) {
+            Some(info) => {
+                let used = info.total - info.available;
+                let swap_used = info.swap_total.saturating_sub(info.swap_free);
+                let percent = get_percent(used, info.total);
+                let text = format(&self.format, |key| match key {
+                    "icon" => Some(ICON.to_string()),
+                    "used" => Some(format_bytes(used)),
+                    "available" => Some(format_bytes(info.available)),
+                    "total" => Some(format_bytes(info.total)),
+                    "percent" => Some(format!("{:.0}", percent)),
+                    "swap_used" => Some(format_bytes(swap_used)),
+                    "swap_total" => Some(format_bytes(info.swap_total)),
+                    "swap_percent" => Some(format!("{:.0}", get_percent(swap_used, info.swap_total))),
+                    _ => None
+                });
+                (text, percent)
+            },
+            None => (format!("{}?", ICON), 0.0)
+        };
+        if self.text != text || force_refresh {
+            label.set_text(&text);
+            self.text = text;
+        }
+        let color = get_threshold_color(percent, self.warning, self.critical);
+        if self.color != Some(color) || force_refresh {
+            let (r, g, b) = color;
+            set_label_color(label, r, g, b);
+            self.color = Some(color);
+        }
+    }
+
+    /// The process list is only read when the tooltip is about to show, and
+    /// at most once per refresh since GTK asks again on every pointer motion.
+    pub fn new(settings: &Settings) -> Label {
+        let label = Label::new(None);
+        label.set_margin_end(7);
+        if !settings.memory.enabled {
+            label.set_no_show_all(true);
+            label.hide();
+            return label;
+        }
+        let mut memory = MemoryWidget {
+            procfs_root: settings.procfs_root.clone(),
+            format: settings.memory.format.clone(),
+            warning: f64::from(settings.memory.warning),
+            critical: f64::from(settings.memory.critical),
+            text: String::new(),
+            color: None
+        };
+        memory.update(&label, true);
+        let (procfs_root, count) = (settings.procfs_root.clone(), settings.memory.processes);
+        let top_processes: Rc<RefCell<Option<(Instant, String)>>> = Rc::new(RefCell::new(None));
+        label.set_has_tooltip(count > 0);
+        label.connect_query_tooltip(move |_, _, _, _, tooltip| {
+            let mut top_processes = top_processes.borrow_mut();
+            let expired = match &*top_processes {
+                Some((read_at, _)) => read_at.elapsed() >= Duration::from_millis(u64::from(REFRESH_RATE)),
+                None => true
+            };
+            if expired {
+                let lines: Vec<String> = get_top_processes(&procfs_root, count).iter()
+                    .map(|(name, rss)| format!("{}\t{}", format_bytes(*rss), name))
+                    .collect();
+                *top_processes = Some((Instant::now(), lines.join("\n")));
+            }
+            match &*top_processes {
+                Some((_, text)) if !text.is_empty() => {
+                    tooltip.set_text(Some(text.as_str()));
+                    true
+                },
+                _ => false
+            }
+        });
+        let label_clone = label.clone();
+        timeout_add(REFRESH_RATE, move || {
+            memory.update(&label_clone, false);
+            Continue(true)
+        });
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn meminfo() {
+        let info = get_memory_info(&fixtures::procfs("thinkpad-t480")).unwrap();
+        assert_eq!(info.total, 16284616 * 1024);
+        assert_eq!(info.available, 9875340 * 1024);
+        assert_eq!(info.swap_total - info.swap_free, 524288 * 1024);
+        assert_eq!(format_bytes(info.total - info.available), "6.1G");
+        assert_eq!(format_bytes(info.total), "15.5G");
+    }
+
+    #[test]
+    fn missing_meminfo() {
+        assert!(get_memory_info(&fixtures::procfs("dell-xps-13")).is_none());
+    }
+
+    #[test]
+    fn top_processes_skip_kernel_threads() {
+        let processes = get_top_processes(&fixtures::procfs("thinkpad-t480"), 5);
+        assert_eq!(processes, vec![
+            (String::from("firefox"), 1843512 * 1024),
+            (String::from("emacs"), 412380 * 1024),
+            (String::from("systemd"), 12644 * 1024)
+        ]);
+        assert_eq!(get_top_processes(&fixtures::procfs("thinkpad-t480"), 1).len(), 1);
+    }
+}
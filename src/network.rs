@@ -0,0 +1,407 @@
+use gtk::{
+    timeout_add,
+    Continue,
+    Label,
+    LabelExt,
+    WidgetExt
+};
+use std::ffi::CStr;
+use std::fs;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::raw::{c_char, c_void};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use crate::format::{format, format_bytes};
+use crate::paint::set_label_color;
+use crate::Settings;
+
+const REFRESH_RATE: u32 = 1000;
+/// Relative to the sysfs root.
+const NET_DIR: &str = "class/net";
+const DEFAULT_FORMAT: &str = "{icon}{essid} {ipv4}";
+const ETHERNET_ICON: &str = "\u{f0e8} ";
+const WIFI_ICON: &str = "\u{f1eb} ";
+const DISCONNECTED_ICON: &str = "\u{f127}";
+/// Maximum link quality of `/proc/net/wireless` for most drivers.
+const MAX_QUALITY: f64 = 70.0;
+const SIOCGIWESSID: libc::c_ulong = 0x8B1B;
+const IW_ESSID_MAX_SIZE: usize = 32;
+
+#[derive(Clone)]
+pub struct NetworkSettings {
+    pub enabled: bool,
+    /// Interface to show, the one of the default route otherwise.
+    pub interface: Option<String>,
+    /// Placeholders are `{icon}`, `{interface}`, `{type}`, `{ipv4}`,
+    /// `{ipv6}`, `{essid}`, `{signal}` (in percent), `{down}` and `{up}`
+    /// (per second).
+    pub format: String
+}
+
+impl Default for NetworkSettings {
+    fn default() -> NetworkSettings {
+        NetworkSettings {
+            enabled: false,
+            interface: None,
+            format: String::from(DEFAULT_FORMAT)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Ethernet,
+    Wifi
+}
+
+struct Interface {
+    name: String,
+    kind: Kind,
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    essid: Option<String>,
+    signal: Option<u8>,
+    rx_bytes: Option<u64>,
+    tx_bytes: Option<u64>
+}
+
+/// `struct iw_point` of `linux/wireless.h`.
+#[repr(C)]
+struct IwPoint {
+    pointer: *mut c_void,
+    length: u16,
+    flags: u16
+}
+
+/// `struct iwreq`, its union only used as an `iw_point` here.
+#[repr(C)]
+struct IwReq {
+    name: [c_char; libc::IFNAMSIZ],
+    essid: IwPoint
+}
+
+pub struct NetworkWidget {
+    net_root: PathBuf,
+    procfs_root: PathBuf,
+    interface: Option<String>,
+    format: String,
+    /// Byte counters of the previous refresh and when they were read, for
+    /// the throughput.
+    last_bytes: Option<(String, u64, u64, Instant)>,
+    text: String,
+    connected: Option<bool>,
+    tooltip: String
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|content| content.trim().to_string())
+}
+
+/// Through `getifaddrs`, IPv4 addresses are not exposed in sysfs or procfs.
+fn get_ipv4(interface: &str) -> Option<Ipv4Addr> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    unsafe {
+        if libc::getifaddrs(&mut ifaddrs) != 0 {
+            return None;
+        }
+        let mut address = None;
+        let mut current = ifaddrs;
+        while !current.is_null() {
+            let ifaddr = &*current;
+            current = ifaddr.ifa_next;
+            if ifaddr.ifa_addr.is_null() || i32::from((*ifaddr.ifa_addr).sa_family) != libc::AF_INET {
+                continue;
+            }
+            if CStr::from_ptr(ifaddr.ifa_name).to_string_lossy() != interface {
+                continue;
+            }
+            let sockaddr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+            address = Some(Ipv4Addr::from(u32::from_be(sockaddr.sin_addr.s_addr)));
+            break;
+        }
+        libc::freeifaddrs(ifaddrs);
+        address
+    }
+}
+
+/// Through the wireless extensions ioctl, only root may use nl80211 scans.
+fn get_essid(interface: &str) -> Option<String> {
+    let mut buffer = [0u8; IW_ESSID_MAX_SIZE + 1];
+    let mut request: IwReq = unsafe { mem::zeroed() };
+    for (target, byte) in request.name.iter_mut().zip(interface.bytes().take(libc::IFNAMSIZ - 1)) {
+        *target = byte as c_char;
+    }
+    request.essid = IwPoint {
+        pointer: buffer.as_mut_ptr() as *mut c_void,
+        length: buffer.len() as u16,
+        flags: 0
+    };
+    unsafe {
+        let socket = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if socket < 0 {
+            return None;
+        }
+        let result = libc::ioctl(socket, SIOCGIWESSID, &mut request);
+        libc::close(socket);
+        if result < 0 {
+            return None;
+        }
+    }
+    let length = (request.essid.length as usize).min(IW_ESSID_MAX_SIZE);
+    let essid = String::from_utf8_lossy(&buffer[..length]).trim_end_matches('\0').to_string();
+    if essid.is_empty() { None } else { Some(essid) }
+}
+
+impl NetworkWidget {
+    /// The interface of the default route in `/proc/net/route`.
+    fn get_default_interface(&self) -> Option<String> {
+        let route = fs::read_to_string(self.procfs_root.join("net/route")).ok()?;
+        route.lines().skip(1).find_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(interface), Some("00000000")) => Some(interface.to_string()),
+                _ => None
+            }
+        })
+    }
+
+    /// First global address of `/proc/net/if_inet6`, or any other one.
+    fn get_ipv6(&self, interface: &str) -> Option<Ipv6Addr> {
+        let content = fs::read_to_string(self.procfs_root.join("net/if_inet6")).ok()?;
+        let mut addresses: Vec<(Ipv6Addr, bool)> = content.lines().filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 6 || fields[5] != interface || fields[0].len() != 32 {
+                return None;
+            }
+            let mut segments = [0u16; 8];
+            for (i, segment) in segments.iter_mut().enumerate() {
+                *segment = u16::from_str_radix(&fields[0][i * 4..i * 4 + 4], 16).ok()?;
+            }
+            Some((Ipv6Addr::from(segments), fields[3] == "00"))
+        }).collect();
+        addresses.sort_by_key(|(_, global)| !global);
+        addresses.first().map(|(address, _)| *address)
+    }
+
+    /// Link quality of `/proc/net/wireless`, in percent.
+    fn get_signal(&self, interface: &str) -> Option<u8> {
+        let content = fs::read_to_string(self.procfs_root.join("net/wireless")).ok()?;
+        content.lines().skip(2).find_map(|line| {
+            let mut fields = line.split_whitespace();
+            if fields.next()?.trim_end_matches(':') != interface {
+                return None;
+            }
+            let quality: f64 = fields.nth(1)?.trim_end_matches('.').parse().ok()?;
+            Some((quality * 100.0 / MAX_QUALITY).round().max(0.0).min(100.0) as u8)
+        })
+    }
+
+    /// The interface as far as sysfs and procfs tell, without the lookups
+    /// through sockets.
+    fn read_interface(&self) -> Option<Interface> {
+        let name = self.interface.clone().or_else(|| self.get_default_interface())?;
+        let path = self.net_root.join(&name);
+        if read_trimmed(&path.join("operstate")).as_ref().map(String::as_str) == Some("down") {
+            return None;
+        }
+        let kind = if path.join("wireless").exists() || path.join("phy80211").exists() {
+            Kind::Wifi
+        } else {
+            Kind::Ethernet
+        };
+        let read_counter = |file: &str| {
+            read_trimmed(&path.join("statistics").join(file)).and_then(|counter| counter.parse().ok())
+        };
+        Some(Interface {
+            kind,
+            ipv4: None,
+            ipv6: self.get_ipv6(&name),
+            essid: None,
+            signal: if kind == Kind::Wifi { self.get_signal(&name) } else { None },
+            rx_bytes: read_counter("rx_bytes"),
+            tx_bytes: read_counter("tx_bytes"),
+            name
+        })
+    }
+
+    fn get_interface(&self) -> Option<Interface> {
+        let mut interface = self.read_interface()?;
+        interface.ipv4 = get_ipv4(&interface.name);
+        if interface.kind == Kind::Wifi {
+            interface.essid = get_essid(&interface.name);
+        }
+        Some(interface)
+    }
+
+    /// Bytes per second since the last refresh, on the same interface. The
+    /// time between two refreshes is measured, timeouts drift and can be
+    /// delayed by a busy main loop.
+    fn get_throughput(&mut self, interface: &Interface, now: Instant) -> Option<(u64, u64)> {
+        let (rx_bytes, tx_bytes) = (interface.rx_bytes?, interface.tx_bytes?);
+        let last_bytes = self.last_bytes.replace((interface.name.clone(), rx_bytes, tx_bytes, now));
+        match last_bytes {
+            Some((name, last_rx, last_tx, last_time)) if name == interface.name => {
+                let elapsed = now.duration_since(last_time);
+                let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+                if seconds <= 0.0 {
+                    return None;
+                }
+                Some(((rx_bytes.saturating_sub(last_rx) as f64 / seconds) as u64,
+                      (tx_bytes.saturating_sub(last_tx) as f64 / seconds) as u64))
+            },
+            _ => None
+        }
+    }
+
+    fn update(&mut self, label: &Label, force_refresh: bool) {
+        let interface = self.get_interface();
+        let throughput = interface.as_ref().and_then(|interface| self.get_throughput(interface, Instant::now()));
+        let (text, tooltip) = match &interface {
+            Some(interface) => {
+                let text = format(&self.format, |key| match key {
+                    "icon" => Some(match interface.kind {
+                        Kind::Ethernet => ETHERNET_ICON,
+                        Kind::Wifi => WIFI_ICON
+                    }.to_string()),
+                    "interface" => Some(interface.name.clone()),
+                    "type" => Some(match interface.kind {
+                        Kind::Ethernet => "ethernet",
+                        Kind::Wifi => "wifi"
+                    }.to_string()),
+                    "ipv4" => interface.ipv4.map(|ipv4| ipv4.to_string()),
+                    "ipv6" => interface.ipv6.map(|ipv6| ipv6.to_string()),
+                    "essid" => interface.essid.clone(),
+                    "signal" => interface.signal.map(|signal| signal.to_string()),
+                    "down" => throughput.map(|(down, _)| format_bytes(down)),
+                    "up" => throughput.map(|(_, up)| format_bytes(up)),
+                    _ => None
+                });
+                let mut tooltip = interface.name.clone();
+                if let Some(essid) = &interface.essid {
+                    tooltip.push_str(&format!(" ({})", essid));
+                }
+                for address in interface.ipv4.map(|ipv4| ipv4.to_string()).iter()
+                    .chain(interface.ipv6.map(|ipv6| ipv6.to_string()).iter()) {
+                    tooltip.push_str(&format!("\n{}", address));
+                }
+                (text.trim().to_string(), tooltip)
+            },
+            None => (DISCONNECTED_ICON.to_string(), String::from("Disconnected"))
+        };
+        if self.text != text || force_refresh {
+            label.set_text(&text);
+            self.text = text;
+        }
+        let connected = interface.is_some();
+        if self.connected != Some(connected) || force_refresh {
+            if connected {
+                set_label_color(label, 255, 255, 255);
+            } else {
+                set_label_color(label, 128, 128, 128);
+            }
+            self.connected = Some(connected);
+        }
+        if self.tooltip != tooltip || force_refresh {
+            label.set_tooltip_text(Some(tooltip.as_str()));
+            self.tooltip = tooltip;
+        }
+    }
+
+    pub fn new(settings: &Settings) -> Label {
+        let label = Label::new(None);
+        label.set_margin_end(7);
+        if !settings.network.enabled {
+            label.set_no_show_all(true);
+            label.hide();
+            return label;
+        }
+        let mut network = NetworkWidget {
+            net_root: settings.sysfs_root.join(NET_DIR),
+            procfs_root: settings.procfs_root.clone(),
+            interface: settings.network.interface.clone(),
+            format: settings.network.format.clone(),
+            last_bytes: None,
+            text: String::new(),
+            connected: None,
+            tooltip: String::new()
+        };
+        network.update(&label, true);
+        let label_clone = label.clone();
+        timeout_add(REFRESH_RATE, move || {
+            network.update(&label_clone, false);
+            Continue(true)
+        });
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use std::time::Duration;
+
+    fn fixture_widget(machine: &str, interface: Option<&str>) -> NetworkWidget {
+        NetworkWidget {
+            net_root: fixtures::sysfs(machine).join(NET_DIR),
+            procfs_root: fixtures::procfs(machine),
+            interface: interface.map(String::from),
+            format: String::from(DEFAULT_FORMAT),
+            last_bytes: None,
+            text: String::new(),
+            connected: None,
+            tooltip: String::new()
+        }
+    }
+
+    #[test]
+    fn default_interface_from_route() {
+        assert_eq!(fixture_widget("thinkpad-t480", None).get_default_interface(), Some(String::from("wlp3s0")));
+        assert_eq!(fixture_widget("dell-xps-13", None).get_default_interface(),
+                   Some(String::from("enx00e04c680001")));
+    }
+
+    #[test]
+    fn global_ipv6_preferred() {
+        let network = fixture_widget("thinkpad-t480", None);
+        assert_eq!(network.get_ipv6("wlp3s0"), Some("2a01:4f8:c0c0:1234::ab".parse().unwrap()));
+        assert_eq!(network.get_ipv6("lo"), Some(Ipv6Addr::LOCALHOST));
+        assert_eq!(fixture_widget("dell-xps-13", None).get_ipv6("enx00e04c680001"), None);
+    }
+
+    #[test]
+    fn signal_from_wireless() {
+        assert_eq!(fixture_widget("thinkpad-t480", None).get_signal("wlp3s0"), Some(77));
+        assert_eq!(fixture_widget("dell-xps-13", None).get_signal("enx00e04c680001"), None);
+    }
+
+    #[test]
+    fn interface_kind_and_counters() {
+        let wifi = fixture_widget("thinkpad-t480", None).read_interface().unwrap();
+        assert_eq!(wifi.name, "wlp3s0");
+        assert!(wifi.kind == Kind::Wifi);
+        assert_eq!(wifi.signal, Some(77));
+        assert_eq!((wifi.rx_bytes, wifi.tx_bytes), (Some(734120554), Some(48201377)));
+        let ethernet = fixture_widget("dell-xps-13", None).read_interface().unwrap();
+        assert!(ethernet.kind == Kind::Ethernet);
+        assert_eq!(ethernet.signal, None);
+    }
+
+    #[test]
+    fn down_interface_is_disconnected() {
+        assert!(fixture_widget("thinkpad-t480", Some("enp0s31f6")).read_interface().is_none());
+    }
+
+    #[test]
+    fn throughput_uses_elapsed_time() {
+        let mut network = fixture_widget("thinkpad-t480", None);
+        let mut interface = network.read_interface().unwrap();
+        let start = Instant::now();
+        assert_eq!(network.get_throughput(&interface, start), None);
+        interface.rx_bytes = interface.rx_bytes.map(|rx_bytes| rx_bytes + 4096);
+        interface.tx_bytes = interface.tx_bytes.map(|tx_bytes| tx_bytes + 1024);
+        // A stalled refresh, 4 seconds instead of 1
+        assert_eq!(network.get_throughput(&interface, start + Duration::from_secs(4)), Some((1024, 256)));
+    }
+}
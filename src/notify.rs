@@ -0,0 +1,39 @@
+use std::collections::HashMap;
+use std::thread;
+use dbus::{BusType, Connection, Message};
+use dbus::arg::Variant;
+
+const DESTINATION: &str = "org.freedesktop.Notifications";
+const PATH: &str = "/org/freedesktop/Notifications";
+const INTERFACE: &str = "org.freedesktop.Notifications";
+const TIMEOUT: i32 = 2000;
+
+#[derive(Clone, Copy)]
+pub enum Urgency {
+    Normal = 1,
+    Critical = 2
+}
+
+/// Sends a desktop notification from another thread, so a missing or slow
+/// notification daemon never blocks the bar.
+pub fn notify(summary: &str, body: &str, urgency: Urgency, debug: bool) {
+    let (summary, body) = (summary.to_string(), body.to_string());
+    thread::spawn(move || {
+        if let Err(e) = send_notification(&summary, &body, urgency) {
+            if debug { println!("{}", e); }
+        }
+    });
+}
+
+fn send_notification(summary: &str, body: &str, urgency: Urgency) -> Result<(), dbus::Error> {
+    let connection = Connection::get_private(BusType::Session)?;
+    let mut hints: HashMap<&str, Variant<u8>> = HashMap::new();
+    hints.insert("urgency", Variant(urgency as u8));
+    let message = Message::new_method_call(DESTINATION, PATH, INTERFACE, "Notify")
+        .map_err(|e| dbus::Error::new_custom("org.freedesktop.DBus.Error.Failed", &e))?
+        .append3("malabar", 0u32, "")
+        .append3(summary, body, Vec::<&str>::new())
+        .append2(hints, -1i32);
+    connection.send_with_reply_and_block(message, TIMEOUT)?;
+    Ok(())
+}
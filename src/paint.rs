@@ -29,3 +29,18 @@ pub fn set_label_scale(label: &Label, scale: f64) {
     attr_list.insert(scale);
     label.set_attributes(&attr_list);
 }
+
+pub const DEFAULT_COLOR: (u8, u8, u8) = (255, 255, 255);
+pub const WARNING_COLOR: (u8, u8, u8) = (250, 189, 47);
+pub const CRITICAL_COLOR: (u8, u8, u8) = (255, 51, 42);
+
+/// White below `warning`, yellow from it and red from `critical`.
+pub fn get_threshold_color(value: f64, warning: f64, critical: f64) -> (u8, u8, u8) {
+    if value >= critical {
+        CRITICAL_COLOR
+    } else if value >= warning {
+        WARNING_COLOR
+    } else {
+        DEFAULT_COLOR
+    }
+}
@@ -1,81 +1,379 @@
 use std::rc::Rc;
 use std::cell::RefCell;
-use glib::Continue;
-use gtk::Orientation::Horizontal;
+use glib::{Continue, SourceId};
+use gtk::Orientation::{Horizontal, Vertical};
+use crate::cover::CoverCache;
+use crate::format::{format, format_duration};
+use crate::marquee::Marquee;
 use crate::paint::{set_label_color, set_label_scale};
 use std::thread;
+use std::time::{Duration, Instant};
 use std::sync::mpsc::{
     channel, 
     Receiver,
     Sender
 };
 use mpris::{
+    DBusError,
+    Event,
+    EventError,
+    LoopStatus,
+    Metadata,
+    MetadataValue,
     PlaybackStatus,
     Player,
-    PlayerFinder,
-    ProgressTick,
-    ProgressTracker
+    PlayerFinder
 };
 use gtk::{
     timeout_add,
     ContainerExt,
+    DrawingArea,
     EventBox,
+    Image,
+    ImageExt,
     Inhibit,
     Label,
     LabelExt,
+    ProgressBar,
+    ProgressBarExt,
+    Tooltip,
+    TooltipExt,
     WidgetExt
 };
 use crate::Settings;
 
-struct EventTracker<'a> {
-    progress_tracker: ProgressTracker<'a>,
-    tx: Sender<String>
+/// Blocks on the player D-Bus signals and forwards them to the widget
+/// `id`, waking the GTK main loop only when something changed.
+struct EventTracker {
+    player: Player<'static>,
+    id: usize,
+    tx: Sender<PlayerMessage>
+}
+
+/// Requests of the controls, run by the control thread on a player of its
+/// own, so that neither finding the player nor clicking blocks the GTK
+/// main loop.
+enum PlayerCommand {
+    /// Bus name of the player the tracking thread now follows.
+    Attach(String),
+    Detach,
+    PlayPause,
+    Previous,
+    Next,
+    Seek(Duration),
+    ChangeVolume(f64),
+    ToggleShuffle,
+    CycleLoopStatus
+}
+
+/// State changes sent by the tracking thread to the widget.
+enum PlayerMessage {
+    /// A player is tracked, the other messages describe it.
+    PlayerFound,
+    Status(PlaybackStatus),
+    Metadata(Metadata),
+    Position(Duration),
+    Shuffle(bool),
+    LoopStatus(LoopStatus),
+    Volume(f64),
+    PlayerGone
+}
+
+#[derive(Clone)]
+pub struct PlayerSettings {
+    /// Placeholders are `{title}`, `{artist}`, `{album}`, `{album_artist}`,
+    /// `{track_number}`, `{disc_number}`, `{url}`, `{art_url}`, `{length}`,
+    /// `{position}`, `{volume}` and any raw metadata key such as `{xesam:genre}`.
+    pub format: String,
+    /// Draws a thin bar under the text, clicking on it seeks.
+    pub progress_bar: bool,
+    /// Shows the elapsed and remaining time next to the text.
+    pub time: bool,
+    /// Shows the `mpris:artUrl` cover before the text.
+    pub art: bool,
+    /// Shows cover, artist, album, title and progress when hovering the player.
+    pub popup: bool,
+    /// Shows a button toggling and reflecting the `Shuffle` property.
+    pub shuffle: bool,
+    /// Shows a button cycling through and reflecting the `LoopStatus` property.
+    pub loop_status: bool
+}
+
+impl Default for PlayerSettings {
+    fn default() -> PlayerSettings {
+        PlayerSettings {
+            format: String::from(DEFAULT_FORMAT),
+            progress_bar: false,
+            time: false,
+            art: false,
+            popup: false,
+            shuffle: false,
+            loop_status: false
+        }
+    }
 }
 
 struct Controls {
-    title: Rc<RefCell<Label>>,
-    play_pause: Rc<RefCell<Label>>
+    container: gtk::Box,
+    title: Marquee,
+    play_pause: Rc<RefCell<Label>>,
+    progress_bar: Option<DrawingArea>,
+    time: Option<Label>,
+    art: Option<Image>,
+    popup: Option<Popup>,
+    shuffle: Option<Label>,
+    loop_status: Option<Label>
+}
+
+/// Content of the tooltip shown when hovering the player.
+#[derive(Clone)]
+struct Popup {
+    container: gtk::Box,
+    cover: Image,
+    title: Label,
+    artist: Label,
+    album: Label,
+    progress_bar: ProgressBar,
+    time: Label
+}
+
+/// Last position reported by the player, extrapolated while playing so the
+/// progress can be redrawn smoothly between two messages.
+struct PlaybackProgress {
+    position: Duration,
+    length: Option<Duration>,
+    updated_at: Instant,
+    playing: bool
 }
 
 pub struct PlayerWidget {
+    id: usize,
+    controls: Controls,
+    rx: Receiver<PlayerMessage>,
+    position_timer: Option<SourceId>,
+    format: String,
     title: String,
-    status: PlaybackStatus
+    metadata: Option<Metadata>,
+    status: PlaybackStatus,
+    volume: f64,
+    progress: Rc<RefCell<PlaybackProgress>>,
+    art_url: Option<String>,
+    covers: CoverCache
 }
 
-const REFRESH_INTERVAL: u32 = 200;
+const POSITION_INTERVAL: u32 = 500;
 const CONTROLS_SCALE: f64 = 1.3;
+const DEFAULT_FORMAT: &str = "{title}";
+const PROGRESS_BAR_HEIGHT: i32 = 3;
+const ART_SIZE: i32 = 24;
+const POPUP_ART_SIZE: i32 = 160;
+const POPUP_WIDTH: i32 = 240;
+const VOLUME_STEP: f64 = 0.05;
+/// Delays between two searches for a player, doubled after each miss.
+const PLAYER_RETRY_MIN: Duration = Duration::from_millis(500);
+const PLAYER_RETRY_MAX: Duration = Duration::from_secs(5);
 
-impl<'a> EventTracker<'a> {
-    fn main_loop(&mut self) {
-        let should_continue = true;
-        let mut should_refresh = true;
+thread_local!(
+    static PLAYER_WIDGETS: RefCell<Vec<PlayerWidget>> = RefCell::new(Vec::new())
+);
 
-        while should_continue {
-            self.tick_progress_and_refresh(should_refresh);
-            should_refresh = false;
+impl EventTracker {
+    /// Follows the player until it goes away. Returns false once the widget
+    /// stopped listening.
+    fn main_loop(&self) -> Result<bool, EventError> {
+        let initial_messages = vec![
+            PlayerMessage::PlayerFound,
+            PlayerMessage::Status(self.player.get_playback_status()?),
+            PlayerMessage::Metadata(self.player.get_metadata()?),
+            PlayerMessage::Position(self.player.get_position()?),
+            PlayerMessage::Shuffle(self.player.get_shuffle().unwrap_or(false)),
+            PlayerMessage::LoopStatus(self.player.get_loop_status().unwrap_or(LoopStatus::None)),
+            PlayerMessage::Volume(self.player.get_volume().unwrap_or(1.0))
+        ];
+        if !self.send(initial_messages) {
+            return Ok(false);
         }
+        for event in self.player.events()? {
+            let messages = match event {
+                Ok(Event::Playing) => vec![
+                    PlayerMessage::Status(PlaybackStatus::Playing),
+                    PlayerMessage::Position(self.player.get_position()?)
+                ],
+                Ok(Event::Paused) => vec![
+                    PlayerMessage::Status(PlaybackStatus::Paused),
+                    PlayerMessage::Position(self.player.get_position()?)
+                ],
+                Ok(Event::Stopped) => vec![
+                    PlayerMessage::Status(PlaybackStatus::Stopped),
+                    PlayerMessage::Position(Duration::from_secs(0))
+                ],
+                Ok(Event::TrackChanged(metadata)) => vec![
+                    PlayerMessage::Metadata(metadata),
+                    PlayerMessage::Position(self.player.get_position()?)
+                ],
+                Ok(Event::Seeked { position_in_us }) => vec![
+                    PlayerMessage::Position(Duration::from_micros(position_in_us))
+                ],
+                Ok(Event::ShuffleToggled(shuffle)) => vec![PlayerMessage::Shuffle(shuffle)],
+                Ok(Event::LoopingChanged(loop_status)) => vec![PlayerMessage::LoopStatus(loop_status)],
+                Ok(Event::VolumeChanged(volume)) => vec![PlayerMessage::Volume(volume)],
+                Ok(Event::PlayerShutDown) => break,
+                Err(e) => return Err(e),
+                Ok(_) => continue
+            };
+            if !self.send(messages) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
-    fn tick_progress_and_refresh(&mut self, should_refresh: bool) {
-        let ProgressTick {
-            progress,
-            progress_changed,
-            track_list_changed,
-            ..
-        } = self.progress_tracker.tick();
-        if progress_changed || track_list_changed || should_refresh {
-            let _playback_string = match progress.playback_status() {
-                PlaybackStatus::Playing => self.tx.send(String::from("status::playing")),
-                PlaybackStatus::Paused => self.tx.send(String::from("status::paused")),
-                PlaybackStatus::Stopped => self.tx.send(String::from("status::stopped")),
-            };
-            if let Some(title) = progress.metadata().title() {
-                self.tx.send(format!("title::{}", title)).unwrap();
+    fn send(&self, messages: Vec<PlayerMessage>) -> bool {
+        send_messages(&self.tx, self.id, messages)
+    }
+}
+
+/// Returns false once the widget `id` stopped listening.
+fn send_messages(tx: &Sender<PlayerMessage>, id: usize, messages: Vec<PlayerMessage>) -> bool {
+    for message in messages {
+        if tx.send(message).is_err() {
+            return false;
+        }
+    }
+    glib::idle_add(move || {
+        PlayerWidget::receive(id);
+        Continue(false)
+    });
+    true
+}
+
+/// For the controls and the tracking thread, the control thread only ends
+/// after a panic.
+fn send_command(commands: &Sender<PlayerCommand>, command: PlayerCommand, debug: bool) {
+    if let Err(e) = commands.send(command) {
+        if debug { println!("{}", e); }
+    }
+}
+
+/// Runs the commands of the controls until the widget goes away, on the
+/// player last attached.
+fn run_commands(rx: Receiver<PlayerCommand>, debug: bool) {
+    let mut player: Option<Player<'static>> = None;
+    for command in rx {
+        match command {
+            PlayerCommand::Attach(bus_name) => {
+                player = PlayerFinder::new().ok()
+                    .and_then(|player_finder| player_finder.find_all().ok())
+                    .and_then(|players| players.into_iter().find(|player| player.bus_name().to_string() == bus_name));
+            },
+            PlayerCommand::Detach => player = None,
+            command => if let Some(player) = &player {
+                if let Err(e) = run_command(player, command) {
+                    if debug { println!("{}", e); }
+                }
             }
         }
     }
 }
 
+/// Players which cannot be controlled reject property changes, the
+/// volume, shuffle and loop commands do nothing on them. Seeking is only
+/// tried on players which allow it.
+fn run_command(player: &Player<'static>, command: PlayerCommand) -> Result<(), DBusError> {
+    match command {
+        PlayerCommand::PlayPause => player.play_pause(),
+        PlayerCommand::Previous => player.previous(),
+        PlayerCommand::Next => player.next(),
+        PlayerCommand::Seek(position) => {
+            if !player.can_seek().unwrap_or(false) {
+                return Ok(());
+            }
+            match player.get_metadata()?.track_id() {
+                Some(track_id) => player.set_position(track_id, &position),
+                None => Ok(())
+            }
+        },
+        PlayerCommand::ChangeVolume(step) if player.can_control().unwrap_or(false) => {
+            player.set_volume((player.get_volume()? + step).max(0.0).min(1.0))
+        },
+        PlayerCommand::ToggleShuffle if player.can_control().unwrap_or(false) => {
+            player.set_shuffle(!player.get_shuffle()?)
+        },
+        PlayerCommand::CycleLoopStatus if player.can_control().unwrap_or(false) => {
+            player.set_loop_status(match player.get_loop_status()? {
+                LoopStatus::None => LoopStatus::Playlist,
+                LoopStatus::Playlist => LoopStatus::Track,
+                LoopStatus::Track => LoopStatus::None
+            })
+        },
+        _ => Ok(())
+    }
+}
+
+fn format_metadata(template: &str, metadata: &Metadata, position: Duration, volume: f64) -> String {
+    format(template, |key| match key {
+        "title" => metadata.title().map(String::from),
+        "artist" => metadata.artists().map(|artists| artists.join(", ")),
+        "album" => metadata.album_name().map(String::from),
+        "album_artist" => metadata.album_artists().map(|artists| artists.join(", ")),
+        "track_number" => metadata.track_number().map(|number| number.to_string()),
+        "disc_number" => metadata.disc_number().map(|number| number.to_string()),
+        "url" => metadata.url().map(String::from),
+        "art_url" => metadata.art_url().map(String::from),
+        "length" => metadata.length().map(format_duration),
+        "position" => Some(format_duration(position)),
+        "volume" => Some(format!("{}%", (volume * 100.0).round())),
+        key => metadata.get(key).and_then(metadata_value_to_string)
+    })
+}
+
+fn duration_to_micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+impl PlaybackProgress {
+    fn current_position(&self) -> Duration {
+        let position = if self.playing {
+            self.position + self.updated_at.elapsed()
+        } else {
+            self.position
+        };
+        match self.length {
+            Some(length) if position > length => length,
+            _ => position
+        }
+    }
+
+    fn fraction(&self) -> f64 {
+        match self.length {
+            Some(length) if length.as_secs() > 0 || length.subsec_nanos() > 0 => {
+                duration_to_micros(self.current_position()) as f64
+                    / duration_to_micros(length) as f64
+            },
+            _ => 0.0
+        }
+    }
+
+    fn time_text(&self) -> String {
+        let position = self.current_position();
+        match self.length {
+            Some(length) if length > position => format!("{} / -{}",
+                                                         format_duration(position),
+                                                         format_duration(length - position)),
+            _ => format_duration(position)
+        }
+    }
+}
+
+fn metadata_value_to_string(value: &MetadataValue) -> Option<String> {
+    value.as_str().map(String::from)
+        .or_else(|| value.as_str_array().map(|values| values.join(", ")))
+        .or_else(|| value.as_i64().map(|number| number.to_string()))
+        .or_else(|| value.as_u64().map(|number| number.to_string()))
+        .or_else(|| value.as_f64().map(|number| number.to_string()))
+        .or_else(|| value.as_bool().map(|boolean| boolean.to_string()))
+}
+
 
 impl PlayerWidget {
     pub fn new(settings: &Settings) -> gtk::Box {
@@ -83,157 +381,508 @@ impl PlayerWidget {
         let play_pause = Label::new(None);
         let next = Label::new(None);
         let hbox = gtk::Box::new(Horizontal, 0);
-        match PlayerFinder::new().unwrap().find_active() {
-            Ok(player) => {
-                let player_rc = Rc::new(player);
-                let title = Label::new(None);
-                set_label_color(&title, 255, 255, 255);
-                title.set_margin_end(10);
-                let previous_event_box = PlayerWidget::set_previous_button(&player_rc, &previous);
-                let play_pause_event_box = PlayerWidget::set_play_pause_button(&player_rc, &play_pause);
-                let next_event_box = PlayerWidget::set_next_button(&player_rc, &next);
-                let title_rc = Rc::new(RefCell::new(title.clone()));
-                let play_pause_rc = Rc::new(RefCell::new(play_pause.clone()));
-                let controls = Controls {
-                    title: title_rc.clone(),
-                    play_pause: play_pause_rc.clone(),
-                };
-                PlayerWidget::spawn_loop_thread(controls, settings.debug);
-                hbox.add(&title);
-                hbox.add(&previous_event_box);
-                hbox.add(&play_pause_event_box);
-                hbox.add(&next_event_box);
-            },
-            Err(e) => if settings.debug { println!("{}", e); }
+        // Shown once a player is found
+        hbox.set_no_show_all(true);
+        let (commands, commands_rx) = channel();
+        let debug = settings.debug;
+        thread::spawn(move || run_commands(commands_rx, debug));
+        let title = Label::new(None);
+        set_label_color(&title, 255, 255, 255);
+        title.set_margin_end(10);
+        let previous_event_box = PlayerWidget::set_previous_button(&commands, &previous, settings.debug);
+        let play_pause_event_box = PlayerWidget::set_play_pause_button(&commands, &play_pause, settings.debug);
+        let next_event_box = PlayerWidget::set_next_button(&commands, &next, settings.debug);
+        let title_marquee = Marquee::new(&title, &settings.marquee);
+        let play_pause_rc = Rc::new(RefCell::new(play_pause.clone()));
+        let progress = Rc::new(RefCell::new(PlaybackProgress {
+            position: Duration::from_secs(0),
+            length: None,
+            updated_at: Instant::now(),
+            playing: false
+        }));
+        let text_box = gtk::Box::new(Vertical, 0);
+        text_box.set_valign(gtk::Align::Center);
+        text_box.add(title_marquee.widget());
+        let progress_bar = if settings.player.progress_bar {
+            let progress_bar = DrawingArea::new();
+            let progress_bar_event_box = PlayerWidget::set_progress_bar(&commands,
+                                                                        &progress_bar,
+                                                                        &progress,
+                                                                        settings.debug);
+            text_box.add(&progress_bar_event_box);
+            Some(progress_bar)
+        } else {
+            None
+        };
+        let time = if settings.player.time {
+            let time = Label::new(None);
+            PlayerWidget::set_default_text_style(&time);
+            Some(time)
+        } else {
+            None
+        };
+        let art = if settings.player.art {
+            let art = Image::new();
+            art.set_margin_end(7);
+            Some(art)
+        } else {
+            None
+        };
+        let popup = if settings.player.popup {
+            let popup = PlayerWidget::build_popup();
+            let popup_clone = popup.clone();
+            hbox.set_has_tooltip(true);
+            hbox.connect_query_tooltip(move |_, _, _, _, tooltip: &Tooltip| {
+                tooltip.set_custom(Some(&popup_clone.container));
+                true
+            });
+            Some(popup)
+        } else {
+            None
+        };
+        let text_event_box = PlayerWidget::set_volume_scroll(&commands, &text_box, settings.debug);
+        let (shuffle, shuffle_event_box) = if settings.player.shuffle {
+            let shuffle = Label::new(None);
+            let shuffle_event_box = PlayerWidget::set_shuffle_button(&commands, &shuffle, settings.debug);
+            (Some(shuffle), Some(shuffle_event_box))
+        } else {
+            (None, None)
+        };
+        let (loop_status, loop_status_event_box) = if settings.player.loop_status {
+            let loop_status = Label::new(None);
+            let loop_status_event_box = PlayerWidget::set_loop_status_button(&commands,
+                                                                              &loop_status,
+                                                                              settings.debug);
+            (Some(loop_status), Some(loop_status_event_box))
+        } else {
+            (None, None)
+        };
+        let controls = Controls {
+            container: hbox.clone(),
+            title: title_marquee,
+            play_pause: play_pause_rc.clone(),
+            progress_bar,
+            time: time.clone(),
+            art: art.clone(),
+            popup,
+            shuffle,
+            loop_status
+        };
+        PlayerWidget::spawn_loop_thread(controls, commands, progress, &settings.player, settings.debug);
+        if let Some(art) = art {
+            hbox.add(&art);
+        }
+        hbox.add(&text_event_box);
+        if let Some(time) = time {
+            hbox.add(&time);
+        }
+        if let Some(shuffle_event_box) = shuffle_event_box {
+            hbox.add(&shuffle_event_box);
+        }
+        hbox.add(&previous_event_box);
+        hbox.add(&play_pause_event_box);
+        hbox.add(&next_event_box);
+        if let Some(loop_status_event_box) = loop_status_event_box {
+            hbox.add(&loop_status_event_box);
         }
         hbox
     }
 
-    fn spawn_loop_thread(controls: Controls, debug: bool) {
+    fn spawn_loop_thread(controls: Controls,
+                         commands: Sender<PlayerCommand>,
+                         progress: Rc<RefCell<PlaybackProgress>>,
+                         player_settings: &PlayerSettings,
+                         debug: bool) {
         let (tx, rx) = channel();
+        let id = PLAYER_WIDGETS.with(|player_widgets| player_widgets.borrow().len());
+        let mut player_widget = PlayerWidget {
+            id,
+            controls,
+            rx,
+            position_timer: None,
+            format: player_settings.format.clone(),
+            title: String::from(""),
+            metadata: None,
+            status: PlaybackStatus::Stopped,
+            volume: 1.0,
+            progress,
+            art_url: None,
+            covers: CoverCache::new(ART_SIZE, POPUP_ART_SIZE)
+        };
+        player_widget.refresh(true);
+        PLAYER_WIDGETS.with(|player_widgets| player_widgets.borrow_mut().push(player_widget));
+        // Players come and go, a new one is looked for whenever none is
+        // tracked. After an error the player may still be there, it is only
+        // gone once it shut down or cannot be found anymore.
         thread::spawn(move || {
-            match PlayerFinder::new().unwrap().find_active() {
-                Ok(player) => {
-                    let progress_tracker = player
-                        .track_progress(REFRESH_INTERVAL)
-                        .expect("Could not determine progress of player");
-
-                    let mut event_tracker = EventTracker {
-                        progress_tracker: progress_tracker,
-                        tx
-                    };
-                    event_tracker.main_loop();
-                },
-                Err(e) => if debug { println!("{}", e); }
+            let mut retry = PLAYER_RETRY_MIN;
+            let mut shown = false;
+            loop {
+                let player = match PlayerFinder::new() {
+                    Ok(player_finder) => player_finder.find_active().ok(),
+                    Err(e) => {
+                        if debug { println!("{}", e); }
+                        None
+                    }
+                };
+                let gone = match player {
+                    Some(player) => {
+                        send_command(&commands, PlayerCommand::Attach(player.bus_name().to_string()), debug);
+                        let event_tracker = EventTracker { player, id, tx: tx.clone() };
+                        shown = true;
+                        retry = PLAYER_RETRY_MIN;
+                        match event_tracker.main_loop() {
+                            Ok(true) => true,
+                            Ok(false) => return,
+                            Err(e) => {
+                                if debug { println!("{}", e); }
+                                false
+                            }
+                        }
+                    },
+                    None => shown
+                };
+                if gone {
+                    send_command(&commands, PlayerCommand::Detach, debug);
+                    if !send_messages(&tx, id, vec![PlayerMessage::PlayerGone]) {
+                        return;
+                    }
+                    shown = false;
+                }
+                thread::sleep(retry);
+                retry = (retry * 2).min(PLAYER_RETRY_MAX);
+            }
+        });
+    }
+
+    fn receive(id: usize) {
+        PLAYER_WIDGETS.with(|player_widgets| {
+            if let Some(player_widget) = player_widgets.borrow_mut().get_mut(id) {
+                player_widget.update();
             }
         });
-        let (title, status) = PlayerWidget::get_data(&rx)
-            .unwrap_or_else(|_| ("".to_string(), PlaybackStatus::Stopped));
-        let mut player_widget = PlayerWidget { title, status };
-        player_widget.update(&rx, &controls, true);
-        timeout_add(REFRESH_INTERVAL, move || {
-            player_widget.update(&rx, &controls, false);
-            Continue(true) 
+    }
+
+    /// Runs only while playing, to move the position forward on screen.
+    fn tick(id: usize) -> Continue {
+        PLAYER_WIDGETS.with(|player_widgets| {
+            if let Some(player_widget) = player_widgets.borrow_mut().get_mut(id) {
+                player_widget.refresh(false);
+            }
         });
+        Continue(true)
     }
 
-    fn get_data(rx: &Receiver<String>) -> Result<(String, PlaybackStatus), &'static str> {
-        let iter = rx.try_iter();
-        let mut title = String::from("");
-        let mut status = PlaybackStatus::Paused;
-        let mut query_count = 0;
-        for query_result in iter {
-            query_count = query_count + 1;
-            let data_split: Vec<&str> = query_result.split("::").collect();
-            let (name, info) = (data_split[0], data_split[1]);
-            match name {
-                "title" => { title = String::from(info); },
-                "status" => {
-                    match info {
-                        "playing" => { status = PlaybackStatus::Playing; }
-                        "paused" => { status = PlaybackStatus::Paused; },
-                        "stopped" => { status = PlaybackStatus::Stopped; },
-                        _ => { return Err("Error getting playback status"); }
-                    }
-                },
-                _ => { return Err("Error: Wrong message type"); }
+    fn set_position_timer(&mut self) {
+        let id = self.id;
+        let playing = self.status == PlaybackStatus::Playing;
+        if playing && self.position_timer.is_none() {
+            self.position_timer = Some(timeout_add(POSITION_INTERVAL, move || PlayerWidget::tick(id)));
+        } else if !playing {
+            if let Some(position_timer) = self.position_timer.take() {
+                glib::source_remove(position_timer);
             }
         }
-        if query_count == 0 { return Err("No player data so far"); }
-        Ok((title, status))
     }
 
-    fn update(&mut self, rx: &Receiver<String>, controls: &Controls, force_refresh: bool) {
+    fn update(&mut self) {
         let Controls {
-            title: title_rc,
-            play_pause: play_pause_rc
-        } = controls;
-        let title = title_rc.clone();
-        let title_borrow = title.borrow();
+            container,
+            play_pause: play_pause_rc,
+            art,
+            popup,
+            shuffle,
+            loop_status,
+            ..
+        } = &self.controls;
         let play_pause = play_pause_rc.clone();
         let play_pause_borrow = play_pause.borrow();
 
-        match PlayerWidget::get_data(rx) {
-            Ok((current_title, current_status)) => {
-                if !&current_title.is_empty()
-                    && &self.title != &current_title
-                        || force_refresh {
-                            title_borrow.set_text(current_title.as_ref());
-                            self.title = current_title;
-                        }
-                if force_refresh
-                    || &self.status != &current_status {
+        for message in self.rx.try_iter() {
+            let mut progress = self.progress.borrow_mut();
+            match message {
+                PlayerMessage::PlayerFound => {
+                    container.set_no_show_all(false);
+                    container.show_all();
+                    // show_all also shows an empty cover
+                    PlayerWidget::set_cover(&mut self.covers, &self.art_url, art, popup);
+                },
+                PlayerMessage::Status(current_status) => {
+                    if &self.status != &current_status {
                         play_pause_borrow.set_text(match current_status {
                             PlaybackStatus::Playing => "▮▮",
                             PlaybackStatus::Paused => "▶",
                             PlaybackStatus::Stopped => "▶"
                         });
+                        progress.position = progress.current_position();
+                        progress.updated_at = Instant::now();
+                        progress.playing = current_status == PlaybackStatus::Playing;
                         self.status = current_status
                     }
-            },
-            Err(_e) => {}
+                },
+                PlayerMessage::Metadata(metadata) => {
+                    progress.length = metadata.length();
+                    if let Some(popup) = popup {
+                        popup.title.set_text(metadata.title().unwrap_or(""));
+                        popup.artist.set_text(&metadata.artists().map(|artists| artists.join(", ")).unwrap_or_default());
+                        popup.album.set_text(metadata.album_name().unwrap_or(""));
+                    }
+                    let art_url = metadata.art_url().map(String::from);
+                    if art_url != self.art_url {
+                        PlayerWidget::set_cover(&mut self.covers, &art_url, art, popup);
+                        self.art_url = art_url;
+                    }
+                    self.metadata = Some(metadata);
+                },
+                PlayerMessage::Position(position) => {
+                    progress.position = position;
+                    progress.updated_at = Instant::now();
+                },
+                PlayerMessage::Shuffle(current_shuffle) => {
+                    if let Some(shuffle) = shuffle {
+                        PlayerWidget::set_toggle_style(shuffle, current_shuffle);
+                    }
+                },
+                PlayerMessage::LoopStatus(current_loop_status) => {
+                    if let Some(loop_status) = loop_status {
+                        loop_status.set_text(match current_loop_status {
+                            LoopStatus::Track => "",
+                            _ => ""
+                        });
+                        PlayerWidget::set_toggle_style(loop_status, current_loop_status != LoopStatus::None);
+                    }
+                },
+                PlayerMessage::Volume(volume) => {
+                    self.volume = volume;
+                },
+                PlayerMessage::PlayerGone => {
+                    progress.playing = false;
+                    self.status = PlaybackStatus::Stopped;
+                    self.metadata = None;
+                    self.art_url = None;
+                    container.hide();
+                }
+            }
+        }
+        self.set_position_timer();
+        self.refresh(false);
+    }
+
+    /// Redraws everything depending on the playback position.
+    fn refresh(&mut self, force_refresh: bool) {
+        let Controls {
+            title: title_marquee,
+            progress_bar,
+            time,
+            popup,
+            ..
+        } = &self.controls;
+        let progress = self.progress.borrow();
+
+        if let Some(metadata) = &self.metadata {
+            let current_title = format_metadata(&self.format,
+                                                metadata,
+                                                progress.current_position(),
+                                                self.volume);
+            if !&current_title.is_empty()
+                && &self.title != &current_title
+                    || force_refresh {
+                        title_marquee.set_text(current_title.as_ref());
+                        self.title = current_title;
+                    }
+        }
+        if let Some(progress_bar) = progress_bar {
+            progress_bar.queue_draw();
+        }
+        if let Some(time) = time {
+            time.set_text(&progress.time_text());
+        }
+        if let Some(popup) = popup {
+            popup.progress_bar.set_fraction(progress.fraction());
+            popup.time.set_text(&progress.time_text());
+        }
+    }
+
+    fn set_cover(covers: &mut CoverCache, art_url: &Option<String>, art: &Option<Image>, popup: &Option<Popup>) {
+        let cover = art_url.as_ref().and_then(|url| covers.get(url));
+        if let Some(art) = art {
+            match &cover {
+                Some(cover) => {
+                    art.set_from_pixbuf(Some(&cover.thumbnail));
+                    art.show();
+                },
+                None => {
+                    art.clear();
+                    art.hide();
+                }
+            }
+        }
+        if let Some(popup) = popup {
+            match &cover {
+                Some(cover) => popup.cover.set_from_pixbuf(Some(&cover.large)),
+                None => popup.cover.clear()
+            }
+        }
+    }
+
+    fn build_popup() -> Popup {
+        let container = gtk::Box::new(Vertical, 4);
+        container.set_size_request(POPUP_WIDTH, -1);
+        let cover = Image::new();
+        let title = Label::new(None);
+        set_label_color(&title, 255, 255, 255);
+        let artist = Label::new(None);
+        let album = Label::new(None);
+        for label in &[&title, &artist, &album] {
+            label.set_line_wrap(true);
+            label.set_max_width_chars(30);
+        }
+        let progress_bar = ProgressBar::new();
+        let time = Label::new(None);
+        container.add(&cover);
+        container.add(&title);
+        container.add(&artist);
+        container.add(&album);
+        container.add(&progress_bar);
+        container.add(&time);
+        container.show_all();
+        Popup { container, cover, title, artist, album, progress_bar, time }
+    }
+
+    /// Clicking seeks, only for players which allow it.
+    fn set_progress_bar(commands: &Sender<PlayerCommand>,
+                        progress_bar: &DrawingArea,
+                        progress: &Rc<RefCell<PlaybackProgress>>,
+                        debug: bool) -> EventBox {
+        progress_bar.set_size_request(-1, PROGRESS_BAR_HEIGHT);
+        let progress_clone = progress.clone();
+        progress_bar.connect_draw(move |widget, ctx| {
+            let width = f64::from(widget.get_allocated_width());
+            let height = f64::from(widget.get_allocated_height());
+            ctx.set_source_rgba(1.0, 1.0, 1.0, 0.2);
+            ctx.rectangle(0.0, 0.0, width, height);
+            ctx.fill();
+            ctx.set_source_rgba(1.0, 1.0, 1.0, 0.9);
+            ctx.rectangle(0.0, 0.0, width * progress_clone.borrow().fraction(), height);
+            ctx.fill();
+            Inhibit(false)
+        });
+        let event_box = EventBox::new();
+        event_box.set_margin_end(10);
+        event_box.add(progress_bar);
+        let commands = commands.clone();
+        let progress_clone = progress.clone();
+        event_box.connect_button_press_event(move |widget, event_button| {
+            let (x, _) = event_button.get_position();
+            let width = f64::from(widget.get_allocated_width());
+            if let Some(length) = progress_clone.borrow().length {
+                let position = Duration::from_micros(
+                    (duration_to_micros(length) as f64 * (x / width).max(0.0).min(1.0)) as u64
+                );
+                send_command(&commands, PlayerCommand::Seek(position), debug);
+            }
+            Inhibit(false)
+        });
+        event_box
+    }
+
+    fn set_volume_scroll(commands: &Sender<PlayerCommand>, text_box: &gtk::Box, debug: bool) -> EventBox {
+        let event_box = EventBox::new();
+        event_box.add_events(gdk::EventMask::SCROLL_MASK.bits() as i32);
+        let commands = commands.clone();
+        event_box.connect_scroll_event(move |_, event_scroll| {
+            let step = match event_scroll.get_direction() {
+                gdk::ScrollDirection::Up => VOLUME_STEP,
+                gdk::ScrollDirection::Down => -VOLUME_STEP,
+                gdk::ScrollDirection::Smooth => -event_scroll.get_delta().1 * VOLUME_STEP,
+                _ => 0.0
+            };
+            send_command(&commands, PlayerCommand::ChangeVolume(step), debug);
+            Inhibit(false)
+        });
+        event_box.add(text_box);
+        event_box
+    }
+
+    /// Active toggles are white, inactive ones grey.
+    fn set_toggle_style(label: &Label, active: bool) {
+        if active {
+            set_label_color(label, 255, 255, 255);
+        } else {
+            set_label_color(label, 128, 128, 128);
         }
     }
 
+    fn set_shuffle_button(commands: &Sender<PlayerCommand>, label: &Label, debug: bool) -> EventBox {
+        label.set_text("");
+        PlayerWidget::set_default_text_style(label);
+        PlayerWidget::set_toggle_style(label, false);
+        set_label_scale(&label, CONTROLS_SCALE);
+        let event_box = EventBox::new();
+        let commands = commands.clone();
+        event_box.connect_button_press_event(move |_, _event_button| {
+            send_command(&commands, PlayerCommand::ToggleShuffle, debug);
+            Inhibit(false)
+        });
+        event_box.add(label);
+        event_box
+    }
+
+    fn set_loop_status_button(commands: &Sender<PlayerCommand>, label: &Label, debug: bool) -> EventBox {
+        label.set_text("");
+        PlayerWidget::set_default_text_style(label);
+        PlayerWidget::set_toggle_style(label, false);
+        set_label_scale(&label, CONTROLS_SCALE);
+        let event_box = EventBox::new();
+        let commands = commands.clone();
+        event_box.connect_button_press_event(move |_, _event_button| {
+            send_command(&commands, PlayerCommand::CycleLoopStatus, debug);
+            Inhibit(false)
+        });
+        event_box.add(label);
+        event_box
+    }
+
     fn set_default_text_style(label: &Label) {
         set_label_color(&label, 255, 255, 255);
         label.set_margin_end(10);
     }
 
-    fn set_play_pause_button(player: &Rc<Player<'static>>, label: &Label) -> EventBox {
+    fn set_play_pause_button(commands: &Sender<PlayerCommand>, label: &Label, debug: bool) -> EventBox {
+        label.set_text("▶");
         PlayerWidget::set_default_text_style(label);
         let event_box = EventBox::new();
-        let player_clone = player.clone();
+        let commands = commands.clone();
         event_box.connect_button_press_event(move |_, _event_button| {
-            player_clone.play_pause().expect("Could not pause");
+            send_command(&commands, PlayerCommand::PlayPause, debug);
             Inhibit(false)
         });
         event_box.add(label);
         event_box
     }
 
-    fn set_previous_button(player: &Rc<Player<'static>>, label: &Label) -> EventBox {
+    fn set_previous_button(commands: &Sender<PlayerCommand>, label: &Label, debug: bool) -> EventBox {
         label.set_text("");
         PlayerWidget::set_default_text_style(label);
         set_label_scale(&label, CONTROLS_SCALE);
         let event_box = EventBox::new();
-        let player_clone = player.clone();
+        let commands = commands.clone();
         event_box.connect_button_press_event(move |_, _event_button| {
-            player_clone.previous().expect("Could not go to previous song");
+            send_command(&commands, PlayerCommand::Previous, debug);
             Inhibit(false)
         });
         event_box.add(label);
         event_box
     }
 
-    fn set_next_button(player: &Rc<Player<'static>>, label: &Label) -> EventBox {
+    fn set_next_button(commands: &Sender<PlayerCommand>, label: &Label, debug: bool) -> EventBox {
         label.set_text("");
         PlayerWidget::set_default_text_style(label);
         set_label_scale(&label, CONTROLS_SCALE);
         let event_box = EventBox::new();
-        let player_clone = player.clone();
+        let commands = commands.clone();
         event_box.connect_button_press_event(move |_, _event_button| {
-            player_clone.next().expect("Could not go to next song");
+            send_command(&commands, PlayerCommand::Next, debug);
             Inhibit(false)
         });
         event_box.add(label);
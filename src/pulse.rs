@@ -0,0 +1,402 @@
+use gtk::{
+    timeout_add,
+    ContainerExt,
+    Continue,
+    EventBox,
+    Inhibit,
+    Label,
+    LabelExt,
+    Menu,
+    MenuExt,
+    MenuItem,
+    MenuItemExt,
+    MenuShellExt,
+    WidgetExt
+};
+use gtk::Orientation::Horizontal;
+use libpulse_binding::callbacks::ListResult;
+use libpulse_binding::context::{Context, State};
+use libpulse_binding::context::subscribe::subscription_masks;
+use libpulse_binding::volume::{ChannelVolumes, Volume, VOLUME_NORM};
+use libpulse_glib_binding::Mainloop;
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+use crate::format::format;
+use crate::paint::set_label_color;
+use crate::volume::get_icon;
+use crate::Settings;
+
+const MIC_ICON: &str = "\u{f130}";
+const MIC_MUTED_ICON: &str = "\u{f131}";
+/// Milliseconds before reconnecting to a lost server, doubled after each
+/// failed attempt.
+const RECONNECT_MIN: u32 = 1000;
+const RECONNECT_MAX: u32 = 30000;
+
+/// Volume of the default sink and mute state of the default source, over
+/// the PulseAudio native protocol (also spoken by pipewire-pulse).
+pub struct PulseWidget {
+    /// Drives the contexts from the GLib main loop, kept alive with them.
+    mainloop: Rc<Mainloop>,
+    context: Context,
+    /// Incremented by each reconnection, so that the state changes of a
+    /// replaced context are ignored.
+    generation: u32,
+    reconnect_delay: u32,
+    debug: bool,
+    container: gtk::Box,
+    label: Label,
+    mic_label: Label,
+    format: String,
+    step: u32,
+    default_sink: Option<String>,
+    default_source: Option<String>,
+    sink_volume: Option<ChannelVolumes>,
+    sink_muted: bool,
+    source_muted: Option<bool>,
+    /// Names and descriptions of all sinks, for the switching menu.
+    sinks: Vec<(String, String)>,
+    text: String,
+    muted: Option<bool>
+}
+
+thread_local!(
+    static PULSE_WIDGETS: RefCell<Vec<PulseWidget>> = RefCell::new(Vec::new())
+);
+
+fn volume_to_percent(volume: Volume) -> u32 {
+    ((f64::from(volume.0) * 100.0 / f64::from(VOLUME_NORM.0)).round()) as u32
+}
+
+fn percent_to_volume(percent: u32) -> Volume {
+    Volume((f64::from(VOLUME_NORM.0) * f64::from(percent) / 100.0).round() as u32)
+}
+
+impl PulseWidget {
+    fn with<F: FnOnce(&mut PulseWidget)>(id: usize, f: F) {
+        PULSE_WIDGETS.with(|pulse_widgets| {
+            if let Some(pulse_widget) = pulse_widgets.borrow_mut().get_mut(id) {
+                f(pulse_widget);
+            }
+        });
+    }
+
+    /// Creates a context for the widget `id` and starts connecting it, along
+    /// with whether connecting could start at all.
+    fn connect(mainloop: &Mainloop, id: usize, generation: u32, debug: bool) -> Option<(Context, bool)> {
+        let mut context = Context::new(mainloop, "malabar")?;
+        context.set_state_callback(Some(Box::new(move || PulseWidget::on_state(id, generation))));
+        match context.connect(None, libpulse_binding::context::flags::NOFLAGS, None) {
+            Ok(_) => Some((context, true)),
+            Err(e) => {
+                if debug { println!("{}", e); }
+                Some((context, false))
+            }
+        }
+    }
+
+    fn schedule_reconnect(&mut self, id: usize) {
+        let delay = self.reconnect_delay;
+        self.reconnect_delay = (delay * 2).min(RECONNECT_MAX);
+        timeout_add(delay, move || {
+            PulseWidget::reconnect(id);
+            Continue(false)
+        });
+    }
+
+    /// A failed context cannot connect again, a new one replaces it. It is
+    /// created outside of the widget borrow since connecting already calls
+    /// the state callback.
+    fn reconnect(id: usize) {
+        let mut state = None;
+        PulseWidget::with(id, |pulse_widget| {
+            state = Some((pulse_widget.mainloop.clone(), pulse_widget.generation + 1, pulse_widget.debug));
+        });
+        let (mainloop, generation, debug) = match state {
+            Some(state) => state,
+            None => return
+        };
+        let connection = PulseWidget::connect(&mainloop, id, generation, debug);
+        PulseWidget::with(id, |pulse_widget| match connection {
+            Some((context, connecting)) => {
+                pulse_widget.context = context;
+                pulse_widget.generation = generation;
+                if !connecting {
+                    pulse_widget.schedule_reconnect(id);
+                }
+            },
+            None => pulse_widget.schedule_reconnect(id)
+        });
+    }
+
+    fn on_state(id: usize, generation: u32) {
+        PulseWidget::with(id, |pulse_widget| {
+            if pulse_widget.generation != generation {
+                return;
+            }
+            match pulse_widget.context.get_state() {
+                State::Ready => {
+                    pulse_widget.context.set_subscribe_callback(Some(Box::new(move |_, _, _| {
+                        PulseWidget::refresh(id);
+                    })));
+                    pulse_widget.context.subscribe(
+                        subscription_masks::SINK
+                            | subscription_masks::SOURCE
+                            | subscription_masks::SINK_INPUT
+                            | subscription_masks::SERVER,
+                        |_| {}
+                    );
+                    pulse_widget.reconnect_delay = RECONNECT_MIN;
+                    pulse_widget.container.show();
+                    PulseWidget::query_server(pulse_widget, id);
+                },
+                State::Failed | State::Terminated => {
+                    // The server went away, e.g. restarted with the session
+                    pulse_widget.container.hide();
+                    pulse_widget.schedule_reconnect(id);
+                },
+                _ => {}
+            }
+        });
+    }
+
+    fn refresh(id: usize) {
+        PulseWidget::with(id, |pulse_widget| PulseWidget::query_server(pulse_widget, id));
+    }
+
+    /// The default devices can change at any time, so every event starts
+    /// from the server info again.
+    fn query_server(pulse_widget: &mut PulseWidget, id: usize) {
+        pulse_widget.context.introspect().get_server_info(move |server_info| {
+            let default_sink = server_info.default_sink_name.as_ref().map(|name| name.to_string());
+            let default_source = server_info.default_source_name.as_ref().map(|name| name.to_string());
+            PulseWidget::with(id, |pulse_widget| {
+                pulse_widget.default_sink = default_sink;
+                pulse_widget.default_source = default_source;
+                pulse_widget.query_devices(id);
+            });
+        });
+    }
+
+    fn query_devices(&mut self, id: usize) {
+        let introspect = self.context.introspect();
+        if let Some(default_sink) = &self.default_sink {
+            introspect.get_sink_info_by_name(default_sink, move |result| {
+                if let ListResult::Item(sink_info) = result {
+                    let (volume, muted) = (sink_info.volume, sink_info.mute);
+                    PulseWidget::with(id, |pulse_widget| {
+                        pulse_widget.sink_volume = Some(volume);
+                        pulse_widget.sink_muted = muted;
+                        pulse_widget.update(false);
+                    });
+                }
+            });
+        }
+        match &self.default_source {
+            Some(default_source) => {
+                introspect.get_source_info_by_name(default_source, move |result| {
+                    if let ListResult::Item(source_info) = result {
+                        let muted = source_info.mute;
+                        PulseWidget::with(id, |pulse_widget| {
+                            pulse_widget.source_muted = Some(muted);
+                            pulse_widget.update(false);
+                        });
+                    }
+                });
+            },
+            None => {
+                self.source_muted = None;
+                self.update(false);
+            }
+        }
+        // Events come in bursts and queries overlap, each one has its own list
+        let mut sinks = Vec::new();
+        introspect.get_sink_info_list(move |result| match result {
+            ListResult::Item(sink_info) => {
+                let name = sink_info.name.as_ref().map(|name| name.to_string()).unwrap_or_default();
+                let description = sink_info.description.as_ref()
+                    .map(|description| description.to_string())
+                    .unwrap_or_else(|| name.clone());
+                sinks.push((name, description));
+            },
+            ListResult::End => {
+                let sinks = mem::replace(&mut sinks, Vec::new());
+                PulseWidget::with(id, |pulse_widget| pulse_widget.sinks = sinks);
+            },
+            ListResult::Error => {}
+        });
+    }
+
+    /// The loudest channel gives the volume.
+    fn update(&mut self, force_refresh: bool) {
+        let percent = self.sink_volume.map(|volume| volume_to_percent(volume.max())).unwrap_or(0);
+        let muted = self.sink_muted;
+        let text = format(&self.format, |key| match key {
+            "icon" => Some(get_icon(percent, muted).to_string()),
+            "percent" => Some(percent.to_string()),
+            _ => None
+        });
+        if self.text != text || force_refresh {
+            self.label.set_text(&text);
+            self.text = text;
+        }
+        if self.muted != Some(muted) || force_refresh {
+            if muted {
+                set_label_color(&self.label, 128, 128, 128);
+            } else {
+                set_label_color(&self.label, 255, 255, 255);
+            }
+            self.muted = Some(muted);
+        }
+        match self.source_muted {
+            Some(true) => {
+                self.mic_label.set_text(MIC_MUTED_ICON);
+                set_label_color(&self.mic_label, 128, 128, 128);
+                self.mic_label.show();
+            },
+            Some(false) => {
+                self.mic_label.set_text(MIC_ICON);
+                set_label_color(&self.mic_label, 255, 255, 255);
+                self.mic_label.show();
+            },
+            None => self.mic_label.hide()
+        }
+    }
+
+    /// Scales all the channels, keeping their balance. Changes are not
+    /// applied to the label here, the server sends an event back.
+    fn change_volume(&mut self, steps: i64) {
+        let (default_sink, mut volume) = match (&self.default_sink, self.sink_volume) {
+            (Some(default_sink), Some(volume)) => (default_sink.clone(), volume),
+            _ => return
+        };
+        let percent = i64::from(volume_to_percent(volume.max())) + steps * i64::from(self.step);
+        volume.scale(percent_to_volume(percent.max(0).min(100) as u32));
+        self.context.introspect().set_sink_volume_by_name(&default_sink, &volume, None);
+    }
+
+    fn toggle_sink_mute(&mut self) {
+        if let Some(default_sink) = &self.default_sink {
+            self.context.introspect().set_sink_mute_by_name(default_sink, !self.sink_muted, None);
+        }
+    }
+
+    fn toggle_source_mute(&mut self) {
+        if let (Some(default_source), Some(muted)) = (&self.default_source, self.source_muted) {
+            self.context.introspect().set_source_mute_by_name(default_source, !muted, None);
+        }
+    }
+
+    /// Lists the sinks, the current default one first marked, activating an
+    /// item makes it the default output.
+    fn show_sinks_menu(&self, id: usize, event_box: &EventBox) {
+        let menu = Menu::new();
+        for (name, description) in &self.sinks {
+            let text = if self.default_sink.as_ref() == Some(name) {
+                format!("\u{2022} {}", description)
+            } else {
+                format!("  {}", description)
+            };
+            let item = MenuItem::new_with_label(&text);
+            let name = name.clone();
+            item.connect_activate(move |_| {
+                let name = name.clone();
+                PulseWidget::with(id, move |pulse_widget| {
+                    pulse_widget.context.set_default_sink(&name, |_| {});
+                });
+            });
+            menu.append(&item);
+        }
+        menu.show_all();
+        menu.popup_at_widget(event_box, gdk::Gravity::SouthWest, gdk::Gravity::NorthWest, None);
+    }
+
+    /// Scroll changes the volume, left click toggles mute, right click
+    /// lists the sinks and a click on the microphone toggles its mute.
+    pub fn new(settings: &Settings) -> gtk::Box {
+        let container = gtk::Box::new(Horizontal, 0);
+        // Shown once connected to the server
+        container.set_no_show_all(true);
+        let event_box = EventBox::new();
+        let label = Label::new(None);
+        label.set_margin_end(7);
+        event_box.add(&label);
+        label.show();
+        event_box.show();
+        container.add(&event_box);
+        let mic_event_box = EventBox::new();
+        let mic_label = Label::new(None);
+        mic_label.set_margin_end(7);
+        mic_label.set_no_show_all(true);
+        mic_event_box.add(&mic_label);
+        mic_event_box.show();
+        container.add(&mic_event_box);
+
+        let mainloop = match Mainloop::new(None) {
+            Some(mainloop) => Rc::new(mainloop),
+            None => return container
+        };
+        let id = PULSE_WIDGETS.with(|pulse_widgets| pulse_widgets.borrow().len());
+        let (context, connecting) = match PulseWidget::connect(&mainloop, id, 0, settings.debug) {
+            Some(connection) => connection,
+            None => return container
+        };
+        let mut pulse_widget = PulseWidget {
+            mainloop,
+            context,
+            generation: 0,
+            reconnect_delay: RECONNECT_MIN,
+            debug: settings.debug,
+            container: container.clone(),
+            label,
+            mic_label,
+            format: settings.volume.format.clone(),
+            step: settings.volume.step,
+            default_sink: None,
+            default_source: None,
+            sink_volume: None,
+            sink_muted: false,
+            source_muted: None,
+            sinks: Vec::new(),
+            text: String::new(),
+            muted: None
+        };
+        // No server yet, e.g. started before the session one
+        if !connecting {
+            pulse_widget.schedule_reconnect(id);
+        }
+        PULSE_WIDGETS.with(|pulse_widgets| pulse_widgets.borrow_mut().push(pulse_widget));
+
+        event_box.add_events(gdk::EventMask::SCROLL_MASK.bits() as i32);
+        event_box.connect_scroll_event(move |_, event_scroll| {
+            let steps = match event_scroll.get_direction() {
+                gdk::ScrollDirection::Up => 1,
+                gdk::ScrollDirection::Down => -1,
+                gdk::ScrollDirection::Smooth => match event_scroll.get_delta().1 {
+                    delta if delta < 0.0 => 1,
+                    delta if delta > 0.0 => -1,
+                    _ => 0
+                },
+                _ => 0
+            };
+            if steps != 0 {
+                PulseWidget::with(id, |pulse_widget| pulse_widget.change_volume(steps));
+            }
+            Inhibit(false)
+        });
+        event_box.connect_button_press_event(move |event_box, event_button| {
+            match event_button.get_button() {
+                1 => PulseWidget::with(id, |pulse_widget| pulse_widget.toggle_sink_mute()),
+                3 => PulseWidget::with(id, |pulse_widget| pulse_widget.show_sinks_menu(id, event_box)),
+                _ => {}
+            }
+            Inhibit(false)
+        });
+        mic_event_box.connect_button_press_event(move |_, _| {
+            PulseWidget::with(id, |pulse_widget| pulse_widget.toggle_source_mute());
+            Inhibit(false)
+        });
+        container
+    }
+}
@@ -0,0 +1,360 @@
+use gtk::{
+    timeout_add,
+    ContainerExt,
+    Continue,
+    Label,
+    LabelExt,
+    WidgetExt
+};
+use gtk::Orientation::Horizontal;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::format::format;
+use crate::paint::{get_threshold_color, set_label_color};
+use crate::Settings;
+
+const REFRESH_RATE: u32 = 2000;
+/// Relative to the sysfs root.
+const HWMON_DIR: &str = "class/hwmon";
+const THERMAL_DIR: &str = "class/thermal";
+const DEFAULT_FORMAT: &str = "{icon}{temp}°C";
+/// Chips tried in order when no sensor is configured.
+const DEFAULT_CHIPS: [&str; 4] = ["coretemp", "k10temp", "zenpower", "acpitz"];
+const DEFAULT_CRITICAL: f64 = 100.0;
+const ICON: &str = "\u{f2c9} ";
+const FAN_ICON: &str = "\u{f863} ";
+
+#[derive(Clone)]
+pub struct TemperatureSettings {
+    pub enabled: bool,
+    /// `chip:label` selectors, e.g. `coretemp:Package id 0`, or a chip
+    /// alone for its first sensor. Chips are hwmon names or thermal zone
+    /// types, stable across reboots unlike the hwmon indexes.
+    pub sensors: Vec<String>,
+    /// Placeholders are `{icon}`, `{temp}`, `{chip}` and `{label}`.
+    pub format: String,
+    /// Degrees turning a sensor yellow.
+    pub warning: u8,
+    /// Degrees turning a sensor red, its own critical trip point otherwise.
+    pub critical: Option<u8>,
+    /// Also show the speed of the spinning fans.
+    pub fans: bool
+}
+
+impl Default for TemperatureSettings {
+    fn default() -> TemperatureSettings {
+        TemperatureSettings {
+            enabled: false,
+            sensors: Vec::new(),
+            format: String::from(DEFAULT_FORMAT),
+            warning: 75,
+            critical: None,
+            fans: false
+        }
+    }
+}
+
+/// Temperatures in degrees Celsius.
+struct Sensor {
+    chip: String,
+    label: String,
+    temp: f64,
+    critical: Option<f64>
+}
+
+struct SensorLabel {
+    selector: String,
+    label: Label,
+    text: String,
+    color: Option<(u8, u8, u8)>
+}
+
+pub struct TemperatureWidget {
+    sysfs_root: PathBuf,
+    format: String,
+    warning: f64,
+    critical: Option<f64>,
+    labels: Vec<SensorLabel>,
+    fan_label: Option<Label>,
+    fan_text: String
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|content| content.trim().to_string())
+}
+
+/// Values of hwmon and thermal zones are in millidegrees.
+fn read_millidegrees(path: &Path) -> Option<f64> {
+    read_trimmed(path)?.parse::<i64>().ok().map(|value| value as f64 / 1000.0)
+}
+
+fn read_dir_sorted(path: &Path) -> Vec<PathBuf> {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(path) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(_) => Vec::new()
+    };
+    entries.sort();
+    entries
+}
+
+/// Indexes of the `<prefix><N>_input` files of a hwmon device, sorted.
+fn get_input_indexes(hwmon: &Path, prefix: &str) -> Vec<u32> {
+    let mut indexes: Vec<u32> = read_dir_sorted(hwmon).iter()
+        .filter_map(|path| path.file_name()?.to_str().map(String::from))
+        .filter_map(|name| {
+            if name.starts_with(prefix) && name.ends_with("_input") {
+                name[prefix.len()..name.len() - "_input".len()].parse().ok()
+            } else {
+                None
+            }
+        })
+        .collect();
+    indexes.sort();
+    indexes
+}
+
+/// Older drivers keep their attributes under `device/`.
+fn get_hwmon_dirs(sysfs_root: &Path) -> Vec<(String, PathBuf)> {
+    read_dir_sorted(&sysfs_root.join(HWMON_DIR)).into_iter().filter_map(|hwmon| {
+        if let Some(name) = read_trimmed(&hwmon.join("name")) {
+            return Some((name, hwmon));
+        }
+        let device = hwmon.join("device");
+        read_trimmed(&device.join("name")).map(|name| (name, device))
+    }).collect()
+}
+
+fn get_hwmon_sensors(sysfs_root: &Path) -> Vec<Sensor> {
+    let mut sensors = Vec::new();
+    for (chip, hwmon) in get_hwmon_dirs(sysfs_root) {
+        for index in get_input_indexes(&hwmon, "temp") {
+            let temp = match read_millidegrees(&hwmon.join(format!("temp{}_input", index))) {
+                Some(temp) => temp,
+                None => continue
+            };
+            sensors.push(Sensor {
+                chip: chip.clone(),
+                label: read_trimmed(&hwmon.join(format!("temp{}_label", index)))
+                    .unwrap_or_else(|| format!("temp{}", index)),
+                temp,
+                critical: read_millidegrees(&hwmon.join(format!("temp{}_crit", index)))
+            });
+        }
+    }
+    sensors
+}
+
+/// Thermal zones have no label, their type is the chip.
+fn get_thermal_sensors(sysfs_root: &Path) -> Vec<Sensor> {
+    read_dir_sorted(&sysfs_root.join(THERMAL_DIR)).into_iter()
+        .filter(|zone| zone.file_name().map_or(false, |name| name.to_string_lossy().starts_with("thermal_zone")))
+        .filter_map(|zone| {
+            let chip = read_trimmed(&zone.join("type"))?;
+            let temp = read_millidegrees(&zone.join("temp"))?;
+            let critical = (0..).map(|i| zone.join(format!("trip_point_{}_type", i)))
+                .take_while(|path| path.exists())
+                .enumerate()
+                .find(|(_, path)| read_trimmed(path).as_ref().map(String::as_str) == Some("critical"))
+                .and_then(|(i, _)| read_millidegrees(&zone.join(format!("trip_point_{}_temp", i))));
+            Some(Sensor { label: chip.clone(), chip, temp, critical })
+        })
+        .collect()
+}
+
+/// Labels and speeds of the fans, stopped ones left out.
+fn get_fans(sysfs_root: &Path) -> Vec<(String, u32)> {
+    let mut fans = Vec::new();
+    for (_, hwmon) in get_hwmon_dirs(sysfs_root) {
+        for index in get_input_indexes(&hwmon, "fan") {
+            let rpm = read_trimmed(&hwmon.join(format!("fan{}_input", index)))
+                .and_then(|rpm| rpm.parse().ok())
+                .unwrap_or(0);
+            if rpm > 0 {
+                let label = read_trimmed(&hwmon.join(format!("fan{}_label", index)))
+                    .unwrap_or_else(|| format!("fan{}", index));
+                fans.push((label, rpm));
+            }
+        }
+    }
+    fans
+}
+
+/// Chips and labels are compared case insensitively, hwmon sensors first.
+fn find_sensor<'a>(sensors: &'a [Sensor], selector: &str) -> Option<&'a Sensor> {
+    let mut tokens = selector.splitn(2, ':');
+    let chip = tokens.next()?;
+    let label = tokens.next();
+    sensors.iter().find(|sensor| {
+        sensor.chip.eq_ignore_ascii_case(chip)
+            && label.map_or(true, |label| sensor.label.eq_ignore_ascii_case(label))
+    })
+}
+
+/// Red from `critical`, the sensor's own critical trip point otherwise.
+fn get_sensor_color(sensor: &Sensor, warning: f64, critical: Option<f64>) -> (u8, u8, u8) {
+    // Some drivers report absurd trip points, such as 0, fall back to the default
+    let critical = critical.or(sensor.critical)
+        .filter(|&critical| critical > warning)
+        .unwrap_or_else(|| DEFAULT_CRITICAL.max(warning));
+    get_threshold_color(sensor.temp, warning, critical)
+}
+
+impl TemperatureWidget {
+    fn update(&mut self, force_refresh: bool) {
+        let mut sensors = get_hwmon_sensors(&self.sysfs_root);
+        sensors.extend(get_thermal_sensors(&self.sysfs_root));
+        for sensor_label in &mut self.labels {
+            let (text, color) = match find_sensor(&sensors, &sensor_label.selector) {
+                Some(sensor) => {
+                    let text = format(&self.format, |key| match key {
+                        "icon" => Some(ICON.to_string()),
+                        "temp" => Some(format!("{:.0}", sensor.temp)),
+                        "chip" => Some(sensor.chip.clone()),
+                        "label" => Some(sensor.label.clone()),
+                        _ => None
+                    });
+                    (text, get_sensor_color(sensor, self.warning, self.critical))
+                },
+                None => (format!("{}?", ICON), (128, 128, 128))
+            };
+            if sensor_label.text != text || force_refresh {
+                sensor_label.label.set_text(&text);
+                sensor_label.text = text;
+            }
+            if sensor_label.color != Some(color) || force_refresh {
+                let (r, g, b) = color;
+                set_label_color(&sensor_label.label, r, g, b);
+                sensor_label.color = Some(color);
+            }
+        }
+        if let Some(fan_label) = &self.fan_label {
+            let fans = get_fans(&self.sysfs_root);
+            let text = fans.iter()
+                .map(|(_, rpm)| format!("{}{}", FAN_ICON, rpm))
+                .collect::<Vec<String>>()
+                .join(" ");
+            if self.fan_text != text || force_refresh {
+                let tooltip: Vec<String> = fans.iter().map(|(label, rpm)| format!("{}: {} RPM", label, rpm)).collect();
+                fan_label.set_text(&text);
+                fan_label.set_tooltip_text(Some(tooltip.join("\n").as_str()));
+                fan_label.set_visible(!text.is_empty());
+                self.fan_text = text;
+            }
+        }
+    }
+
+    /// One label per configured sensor, the first available default chip
+    /// otherwise, and one for the fans.
+    pub fn new(settings: &Settings) -> gtk::Box {
+        let container = gtk::Box::new(Horizontal, 0);
+        if !settings.temperature.enabled {
+            container.set_no_show_all(true);
+            container.hide();
+            return container;
+        }
+        let mut selectors = settings.temperature.sensors.clone();
+        if selectors.is_empty() {
+            let mut sensors = get_hwmon_sensors(&settings.sysfs_root);
+            sensors.extend(get_thermal_sensors(&settings.sysfs_root));
+            let chip = DEFAULT_CHIPS.iter()
+                .find(|chip| find_sensor(&sensors, chip).is_some())
+                .map(|chip| chip.to_string())
+                .or_else(|| sensors.first().map(|sensor| format!("{}:{}", sensor.chip, sensor.label)));
+            selectors.extend(chip);
+        }
+        let labels = selectors.into_iter().map(|selector| {
+            let label = Label::new(None);
+            label.set_margin_end(7);
+            label.set_tooltip_text(Some(selector.as_str()));
+            container.add(&label);
+            SensorLabel { selector, label, text: String::new(), color: None }
+        }).collect();
+        let fan_label = if settings.temperature.fans {
+            let fan_label = Label::new(None);
+            fan_label.set_margin_end(7);
+            fan_label.set_no_show_all(true);
+            set_label_color(&fan_label, 255, 255, 255);
+            container.add(&fan_label);
+            Some(fan_label)
+        } else {
+            None
+        };
+        let mut temperature = TemperatureWidget {
+            sysfs_root: settings.sysfs_root.clone(),
+            format: settings.temperature.format.clone(),
+            warning: f64::from(settings.temperature.warning),
+            critical: settings.temperature.critical.map(f64::from),
+            labels,
+            fan_label,
+            fan_text: String::new()
+        };
+        temperature.update(true);
+        timeout_add(REFRESH_RATE, move || {
+            temperature.update(false);
+            Continue(true)
+        });
+        container
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::paint::{CRITICAL_COLOR, DEFAULT_COLOR, WARNING_COLOR};
+
+    fn get_fixture_sensors() -> Vec<Sensor> {
+        let sysfs_root = fixtures::sysfs("thinkpad-t480");
+        let mut sensors = get_hwmon_sensors(&sysfs_root);
+        sensors.extend(get_thermal_sensors(&sysfs_root));
+        sensors
+    }
+
+    #[test]
+    fn select_by_chip_and_label() {
+        let sensors = get_fixture_sensors();
+        let package = find_sensor(&sensors, "coretemp:Package id 0").unwrap();
+        assert_eq!(package.temp, 83.0);
+        assert_eq!(package.critical, Some(100.0));
+        assert_eq!(find_sensor(&sensors, "CoreTemp:core 1").unwrap().temp, 53.0);
+        // A chip alone is its first sensor
+        assert_eq!(find_sensor(&sensors, "coretemp").unwrap().label, "Package id 0");
+        assert_eq!(find_sensor(&sensors, "thinkpad").unwrap().label, "temp1");
+        assert!(find_sensor(&sensors, "coretemp:Core 9").is_none());
+        assert!(find_sensor(&sensors, "nvme").is_none());
+    }
+
+    #[test]
+    fn thermal_zones() {
+        let sensors = get_fixture_sensors();
+        let package = find_sensor(&sensors, "x86_pkg_temp").unwrap();
+        assert_eq!(package.temp, 83.0);
+        // Only a passive trip point
+        assert_eq!(package.critical, None);
+        // Found in hwmon first, the thermal zone comes after
+        let acpitz: Vec<&Sensor> = sensors.iter().filter(|sensor| sensor.chip == "acpitz").collect();
+        assert_eq!(acpitz.len(), 2);
+        assert_eq!(acpitz[1].critical, Some(128.0));
+    }
+
+    #[test]
+    fn critical_trip_point_coloring() {
+        let sensors = get_fixture_sensors();
+        let package = find_sensor(&sensors, "coretemp:Package id 0").unwrap();
+        assert_eq!(get_sensor_color(package, 75.0, None), WARNING_COLOR);
+        assert_eq!(get_sensor_color(package, 90.0, None), DEFAULT_COLOR);
+        assert_eq!(get_sensor_color(package, 75.0, Some(80.0)), CRITICAL_COLOR);
+        let broken = Sensor { chip: String::from("broken"), label: String::new(), temp: 50.0, critical: Some(0.0) };
+        assert_eq!(get_sensor_color(&broken, 75.0, None), DEFAULT_COLOR);
+        let broken = Sensor { temp: 80.0, ..broken };
+        assert_eq!(get_sensor_color(&broken, 75.0, None), WARNING_COLOR);
+        let sensor = Sensor { chip: String::from("acpitz"), label: String::new(), temp: 130.0, critical: Some(128.0) };
+        assert_eq!(get_sensor_color(&sensor, 75.0, None), CRITICAL_COLOR);
+    }
+
+    #[test]
+    fn spinning_fans_only() {
+        assert_eq!(get_fans(&fixtures::sysfs("thinkpad-t480")), vec![(String::from("fan1"), 2617)]);
+        assert!(get_fans(&fixtures::sysfs("desktop")).is_empty());
+    }
+}
@@ -0,0 +1,524 @@
+use gtk::{
+    timeout_add,
+    ContainerExt,
+    EventBox,
+    Inhibit,
+    Label,
+    LabelExt,
+    WidgetExt
+};
+use glib::{Continue, SourceId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::command::spawn_detached;
+use crate::format::format_duration;
+use crate::notify::{notify, Urgency};
+use crate::paint::set_label_color;
+use crate::Settings;
+
+const TICK_INTERVAL: u32 = 1000;
+const DEFAULT_DURATION: u64 = 5 * 60;
+const DURATION_STEP: u64 = 60;
+const MAX_DURATION: u64 = 24 * 3600;
+const POMODORO_WORK: u64 = 25 * 60;
+const POMODORO_SHORT_BREAK: u64 = 5 * 60;
+const POMODORO_LONG_BREAK: u64 = 15 * 60;
+/// Work sessions before a long break.
+const POMODORO_CYCLE: u32 = 4;
+const STATE_FILE: &str = "malabar/timer";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TimerMode {
+    Countdown,
+    Stopwatch,
+    Pomodoro
+}
+
+#[derive(Clone)]
+pub struct TimerSettings {
+    pub enabled: bool,
+    /// Mode used until another one is picked with a middle click.
+    pub mode: TimerMode,
+    /// Shell command run when a countdown or a pomodoro phase ends.
+    pub command: Option<String>
+}
+
+impl Default for TimerSettings {
+    fn default() -> TimerSettings {
+        TimerSettings {
+            enabled: false,
+            mode: TimerMode::Countdown,
+            command: None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Work,
+    ShortBreak,
+    LongBreak
+}
+
+/// Everything persisted across restarts. A running timer keeps the time it
+/// was started at, so it goes on counting while the bar is down.
+struct TimerState {
+    mode: TimerMode,
+    /// Of the countdown.
+    duration: u64,
+    phase: Phase,
+    /// Work sessions done in the current cycle.
+    pomodoros: u32,
+    /// Seconds counted before the last start.
+    elapsed: u64,
+    /// Unix time of the last start, while running.
+    started: Option<u64>
+}
+
+/// A single timer shared by the bars of all monitors.
+pub struct TimerWidget {
+    state: TimerState,
+    labels: Vec<Label>,
+    /// Whether the labels are colored as running.
+    running: Option<bool>,
+    tick: Option<SourceId>,
+    command: Option<String>,
+    debug: bool
+}
+
+thread_local!(
+    static TIMER_WIDGET: RefCell<Option<TimerWidget>> = RefCell::new(None)
+);
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0)
+}
+
+fn get_state_path() -> Option<PathBuf> {
+    glib::get_user_data_dir().map(|data_dir| data_dir.join(STATE_FILE))
+}
+
+impl TimerMode {
+    fn from_name(name: &str) -> Option<TimerMode> {
+        match name {
+            "countdown" => Some(TimerMode::Countdown),
+            "stopwatch" => Some(TimerMode::Stopwatch),
+            "pomodoro" => Some(TimerMode::Pomodoro),
+            _ => None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TimerMode::Countdown => "countdown",
+            TimerMode::Stopwatch => "stopwatch",
+            TimerMode::Pomodoro => "pomodoro"
+        }
+    }
+
+    fn next(self) -> TimerMode {
+        match self {
+            TimerMode::Countdown => TimerMode::Stopwatch,
+            TimerMode::Stopwatch => TimerMode::Pomodoro,
+            TimerMode::Pomodoro => TimerMode::Countdown
+        }
+    }
+}
+
+impl Phase {
+    fn from_name(name: &str) -> Option<Phase> {
+        match name {
+            "work" => Some(Phase::Work),
+            "short_break" => Some(Phase::ShortBreak),
+            "long_break" => Some(Phase::LongBreak),
+            _ => None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Work => "work",
+            Phase::ShortBreak => "short_break",
+            Phase::LongBreak => "long_break"
+        }
+    }
+
+    fn duration(self) -> u64 {
+        match self {
+            Phase::Work => POMODORO_WORK,
+            Phase::ShortBreak => POMODORO_SHORT_BREAK,
+            Phase::LongBreak => POMODORO_LONG_BREAK
+        }
+    }
+}
+
+impl TimerState {
+    fn new(mode: TimerMode) -> TimerState {
+        TimerState {
+            mode,
+            duration: DEFAULT_DURATION,
+            phase: Phase::Work,
+            pomodoros: 0,
+            elapsed: 0,
+            started: None
+        }
+    }
+
+    /// Falls back to a fresh state on any missing or invalid value.
+    fn load(mode: TimerMode) -> TimerState {
+        let mut state = TimerState::new(mode);
+        let content = match get_state_path().and_then(|path| fs::read_to_string(path).ok()) {
+            Some(content) => content,
+            None => return state
+        };
+        let values: HashMap<&str, &str> = content.lines()
+            .filter_map(|line| {
+                let mut tokens = line.splitn(2, '=');
+                match (tokens.next(), tokens.next()) {
+                    (Some(key), Some(value)) => Some((key, value.trim())),
+                    _ => None
+                }
+            })
+            .collect();
+        let parse = |key: &str| values.get(key).and_then(|value| value.parse::<u64>().ok());
+        if let (Some(mode), Some(phase), Some(duration), Some(pomodoros), Some(elapsed)) = (
+            values.get("mode").and_then(|mode| TimerMode::from_name(mode)),
+            values.get("phase").and_then(|phase| Phase::from_name(phase)),
+            parse("duration"),
+            parse("pomodoros"),
+            parse("elapsed")
+        ) {
+            state.mode = mode;
+            state.phase = phase;
+            state.duration = duration.min(MAX_DURATION);
+            state.pomodoros = pomodoros as u32;
+            state.elapsed = elapsed;
+            state.started = parse("started");
+        }
+        state.catch_up(unix_now());
+        state
+    }
+
+    /// Skips what ended while the bar was down: a countdown is over, and
+    /// pomodoro phases went on one after the other.
+    fn catch_up(&mut self, now: u64) {
+        let started = match self.started {
+            Some(started) => started,
+            None => return
+        };
+        let mut elapsed = self.elapsed + now.saturating_sub(started);
+        match self.mode {
+            TimerMode::Countdown if elapsed >= self.duration => self.reset(),
+            TimerMode::Pomodoro if elapsed >= self.phase.duration() => {
+                while elapsed >= self.phase.duration() {
+                    elapsed -= self.phase.duration();
+                    self.advance_phase();
+                }
+                self.elapsed = 0;
+                self.started = Some(now - elapsed);
+            },
+            _ => {}
+        }
+    }
+
+    fn save(&self) -> Result<(), std::io::Error> {
+        let path = match get_state_path() {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut content = format!(
+            "mode={}\nduration={}\nphase={}\npomodoros={}\nelapsed={}\n",
+            self.mode.name(), self.duration, self.phase.name(), self.pomodoros, self.elapsed
+        );
+        if let Some(started) = self.started {
+            content.push_str(&format!("started={}\n", started));
+        }
+        fs::write(path, content)
+    }
+
+    fn get_elapsed(&self) -> u64 {
+        self.elapsed + self.started.map_or(0, |started| unix_now().saturating_sub(started))
+    }
+
+    /// `None` for the stopwatch, which only counts up.
+    fn get_target(&self) -> Option<u64> {
+        match self.mode {
+            TimerMode::Countdown => Some(self.duration),
+            TimerMode::Stopwatch => None,
+            TimerMode::Pomodoro => Some(self.phase.duration())
+        }
+    }
+
+    fn reset(&mut self) {
+        self.elapsed = 0;
+        self.started = None;
+        if self.mode == TimerMode::Pomodoro {
+            self.phase = Phase::Work;
+            self.pomodoros = 0;
+        }
+    }
+
+    fn toggle(&mut self) {
+        if self.started.is_some() {
+            self.elapsed = self.get_elapsed();
+            self.started = None;
+        } else {
+            self.started = Some(unix_now());
+        }
+    }
+
+    fn advance_phase(&mut self) {
+        self.phase = match self.phase {
+            Phase::Work => {
+                self.pomodoros += 1;
+                if self.pomodoros >= POMODORO_CYCLE {
+                    self.pomodoros = 0;
+                    Phase::LongBreak
+                } else {
+                    Phase::ShortBreak
+                }
+            },
+            Phase::ShortBreak | Phase::LongBreak => Phase::Work
+        };
+    }
+
+    /// Moves to the next pomodoro phase, which starts right away.
+    fn next_phase(&mut self) {
+        self.advance_phase();
+        self.elapsed = 0;
+        self.started = Some(unix_now());
+    }
+
+    fn icon(&self) -> &'static str {
+        match (self.mode, self.phase) {
+            (TimerMode::Countdown, _) => "\u{f254} ",
+            (TimerMode::Stopwatch, _) => "\u{f017} ",
+            (TimerMode::Pomodoro, Phase::Work) => "\u{f0ae} ",
+            (TimerMode::Pomodoro, _) => "\u{f0f4} "
+        }
+    }
+
+    fn text(&self) -> String {
+        let elapsed = self.get_elapsed();
+        let shown = match self.get_target() {
+            Some(target) => target.saturating_sub(elapsed),
+            None => elapsed
+        };
+        format!("{}{}", self.icon(), format_duration(Duration::from_secs(shown)))
+    }
+}
+
+impl TimerWidget {
+    fn with<F: FnOnce(&mut TimerWidget)>(f: F) {
+        TIMER_WIDGET.with(|timer_widget| {
+            if let Some(timer_widget) = timer_widget.borrow_mut().as_mut() {
+                f(timer_widget);
+            }
+        });
+    }
+
+    fn refresh(&mut self) {
+        let text = self.state.text();
+        let running = self.state.started.is_some();
+        for label in &self.labels {
+            label.set_text(&text);
+            if self.running != Some(running) {
+                if running {
+                    set_label_color(label, 255, 255, 255);
+                } else {
+                    set_label_color(label, 128, 128, 128);
+                }
+            }
+        }
+        self.running = Some(running);
+    }
+
+    /// Saves and redraws after a change made by the user.
+    fn changed(&mut self) {
+        if let Err(e) = self.state.save() {
+            if self.debug { println!("{}", e); }
+        }
+        self.set_tick();
+        self.refresh();
+    }
+
+    /// Runs only while the timer is running.
+    fn set_tick(&mut self) {
+        let running = self.state.started.is_some();
+        if running && self.tick.is_none() {
+            self.tick = Some(timeout_add(TICK_INTERVAL, || {
+                TimerWidget::with(|timer_widget| timer_widget.update());
+                Continue(true)
+            }));
+        } else if !running {
+            if let Some(tick) = self.tick.take() {
+                glib::source_remove(tick);
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        let ended = match self.state.get_target() {
+            Some(target) => self.state.started.is_some() && self.state.get_elapsed() >= target,
+            None => false
+        };
+        if ended {
+            self.end();
+        }
+        self.refresh();
+    }
+
+    fn end(&mut self) {
+        let summary = match (self.state.mode, self.state.phase) {
+            (TimerMode::Pomodoro, Phase::Work) => "Time for a break",
+            (TimerMode::Pomodoro, _) => "Back to work",
+            _ => "Timer done"
+        };
+        notify(summary, "", Urgency::Normal, self.debug);
+        if let Some(command) = &self.command {
+            if let Err(e) = spawn_detached(Command::new("sh").arg("-c").arg(command)) {
+                if self.debug { println!("{}", e); }
+            }
+        }
+        match self.state.mode {
+            TimerMode::Pomodoro => self.state.next_phase(),
+            _ => self.state.reset()
+        }
+        self.changed();
+    }
+
+    fn scroll(&mut self, steps: i64) {
+        if self.state.mode != TimerMode::Countdown {
+            return;
+        }
+        let duration = self.state.duration as i64 + steps * DURATION_STEP as i64;
+        self.state.duration = (duration.max(DURATION_STEP as i64) as u64).min(MAX_DURATION);
+        self.changed();
+    }
+
+    fn build_label() -> (EventBox, Label) {
+        let event_box = EventBox::new();
+        let label = Label::new(None);
+        label.set_margin_end(7);
+        event_box.add(&label);
+        event_box.add_events(gdk::EventMask::SCROLL_MASK.bits() as i32);
+        event_box.connect_button_press_event(|_, event_button| {
+            let button = event_button.get_button();
+            TimerWidget::with(|timer_widget| {
+                match button {
+                    1 => timer_widget.state.toggle(),
+                    2 => {
+                        timer_widget.state.mode = timer_widget.state.mode.next();
+                        timer_widget.state.reset();
+                    },
+                    3 => timer_widget.state.reset(),
+                    _ => return
+                }
+                timer_widget.changed();
+            });
+            Inhibit(false)
+        });
+        event_box.connect_scroll_event(|_, event_scroll| {
+            let steps = match event_scroll.get_direction() {
+                gdk::ScrollDirection::Up => 1,
+                gdk::ScrollDirection::Down => -1,
+                gdk::ScrollDirection::Smooth => match event_scroll.get_delta().1 {
+                    delta if delta < 0.0 => 1,
+                    delta if delta > 0.0 => -1,
+                    _ => 0
+                },
+                _ => 0
+            };
+            if steps != 0 {
+                TimerWidget::with(|timer_widget| timer_widget.scroll(steps));
+            }
+            Inhibit(false)
+        });
+        (event_box, label)
+    }
+
+    /// Scroll sets the countdown duration, left click starts or pauses,
+    /// right click resets and middle click switches between countdown,
+    /// stopwatch and pomodoro.
+    pub fn new(settings: &Settings) -> EventBox {
+        let (event_box, label) = TimerWidget::build_label();
+        if !settings.timer.enabled {
+            event_box.set_no_show_all(true);
+            event_box.hide();
+            return event_box;
+        }
+        TIMER_WIDGET.with(|timer_widget| {
+            let mut timer_widget = timer_widget.borrow_mut();
+            if timer_widget.is_none() {
+                *timer_widget = Some(TimerWidget {
+                    state: TimerState::load(settings.timer.mode),
+                    labels: Vec::new(),
+                    running: None,
+                    tick: None,
+                    command: settings.timer.command.clone(),
+                    debug: settings.debug
+                });
+            }
+        });
+        TimerWidget::with(|timer_widget| {
+            timer_widget.labels.push(label);
+            // Colors the new label too
+            timer_widget.running = None;
+            timer_widget.set_tick();
+            timer_widget.update();
+        });
+        event_box
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running(mode: TimerMode, phase: Phase, started: u64) -> TimerState {
+        TimerState { phase, started: Some(started), ..TimerState::new(mode) }
+    }
+
+    #[test]
+    fn countdown_ended_while_down() {
+        let mut state = running(TimerMode::Countdown, Phase::Work, 1000);
+        state.catch_up(1000 + DEFAULT_DURATION - 1);
+        assert_eq!(state.started, Some(1000));
+        state.catch_up(1000 + DEFAULT_DURATION);
+        assert_eq!((state.elapsed, state.started), (0, None));
+    }
+
+    #[test]
+    fn pomodoro_phases_passed_while_down() {
+        // Work, short break, then 2 minutes into the next work session
+        let mut state = running(TimerMode::Pomodoro, Phase::Work, 1000);
+        let now = 1000 + POMODORO_WORK + POMODORO_SHORT_BREAK + 120;
+        state.catch_up(now);
+        assert!(state.phase == Phase::Work);
+        assert_eq!(state.pomodoros, 1);
+        assert_eq!((state.elapsed, state.started), (0, Some(now - 120)));
+        // The fourth work session ends on a long break
+        let mut state = running(TimerMode::Pomodoro, Phase::Work, 1000);
+        state.pomodoros = POMODORO_CYCLE - 1;
+        state.catch_up(1000 + POMODORO_WORK + 60);
+        assert!(state.phase == Phase::LongBreak);
+        assert_eq!(state.pomodoros, 0);
+    }
+
+    #[test]
+    fn paused_or_stopwatch_untouched() {
+        let mut state = TimerState { elapsed: 30, ..TimerState::new(TimerMode::Pomodoro) };
+        state.catch_up(u64::from(u32::max_value()));
+        assert_eq!((state.elapsed, state.started), (30, None));
+        let mut state = running(TimerMode::Stopwatch, Phase::Work, 1000);
+        state.catch_up(1000 + MAX_DURATION);
+        assert_eq!(state.started, Some(1000));
+    }
+}
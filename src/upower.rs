@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use dbus::{BusType, Connection, ConnectionItem, Message, Path};
+use dbus::arg::{RefArg, Variant};
+use dbus::stdintf::org_freedesktop_dbus::Properties;
+use glib::Continue;
+use crate::battery::{Battery, BatteryWidget, State};
+
+const UPOWER: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const DEVICE_INTERFACE: &str = "org.freedesktop.UPower.Device";
+const DISPLAY_DEVICE_PATH: &str = "/org/freedesktop/UPower/devices/DisplayDevice";
+const TIMEOUT: i32 = 2000;
+
+/// `Type` values of `org.freedesktop.UPower.Device`.
+const TYPE_LINE_POWER: u64 = 1;
+const TYPE_BATTERY: u64 = 2;
+
+type DeviceProperties = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// Everything UPower knows about, sent to the widget on every change.
+pub struct UPowerDevices {
+    /// The composite battery UPower computes from all laptop batteries.
+    pub display: Option<Battery>,
+    /// Laptop batteries, powering the machine.
+    pub batteries: Vec<Battery>,
+    /// Mice, keyboards, headsets, ...
+    pub peripherals: Vec<Battery>
+}
+
+/// Properties of the display device and of the other devices by path, kept
+/// between signals so that a change only touches the device it is about.
+struct DeviceCache {
+    display: DeviceProperties,
+    devices: HashMap<String, DeviceProperties>
+}
+
+/// Reads all devices once, then only the ones UPower signals a property
+/// change of, or coming and going. Runs until the widget `id` drops its
+/// receiver.
+pub fn watch(bus: BusType, id: usize, tx: Sender<UPowerDevices>) -> Result<(), dbus::Error> {
+    watch_connection(&Connection::get_private(bus)?, id, tx)
+}
+
+fn watch_connection(connection: &Connection, id: usize, tx: Sender<UPowerDevices>) -> Result<(), dbus::Error> {
+    connection.add_match(&format!(
+        "type='signal',sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'",
+        UPOWER
+    ))?;
+    connection.add_match(&format!("type='signal',sender='{}',interface='{}'", UPOWER, UPOWER))?;
+    let mut cache = DeviceCache::load(connection)?;
+    if !send(&tx, id, cache.get_devices()) {
+        return Ok(());
+    }
+    for item in connection.iter(-1) {
+        if let ConnectionItem::Signal(message) = item {
+            if cache.update(connection, &message) && !send(&tx, id, cache.get_devices()) {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns false once the widget stopped listening.
+fn send(tx: &Sender<UPowerDevices>, id: usize, devices: UPowerDevices) -> bool {
+    if tx.send(devices).is_err() {
+        return false;
+    }
+    glib::idle_add(move || {
+        BatteryWidget::receive(id);
+        Continue(false)
+    });
+    true
+}
+
+impl DeviceCache {
+    fn load(connection: &Connection) -> Result<DeviceCache, dbus::Error> {
+        let message = Message::new_method_call(UPOWER, UPOWER_PATH, UPOWER, "EnumerateDevices")
+            .map_err(|e| dbus::Error::new_custom("org.freedesktop.DBus.Error.Failed", &e))?;
+        let paths: Vec<Path<'static>> = connection.send_with_reply_and_block(message, TIMEOUT)?.read1()?;
+        let mut devices = HashMap::new();
+        for path in paths {
+            // Unplugged between the enumeration and now otherwise
+            if let Ok(properties) = get_properties(connection, &path) {
+                devices.insert(path.to_string(), properties);
+            }
+        }
+        Ok(DeviceCache {
+            display: get_properties(connection, &Path::from(DISPLAY_DEVICE_PATH))?,
+            devices
+        })
+    }
+
+    /// Applies a signal, returns whether anything changed.
+    fn update(&mut self, connection: &Connection, message: &Message) -> bool {
+        let (path, interface, member) = match message.headers() {
+            (_, Some(path), Some(interface), Some(member)) => (path, interface, member),
+            _ => return false
+        };
+        match (interface.as_str(), member.as_str()) {
+            ("org.freedesktop.DBus.Properties", "PropertiesChanged") => {
+                match message.get2::<&str, DeviceProperties>() {
+                    (Some(DEVICE_INTERFACE), Some(changed)) => {
+                        let properties = match path.as_str() {
+                            DISPLAY_DEVICE_PATH => Some(&mut self.display),
+                            _ => self.devices.get_mut(&path)
+                        };
+                        match properties {
+                            Some(properties) => {
+                                properties.extend(changed);
+                                true
+                            },
+                            None => self.refresh(connection, path)
+                        }
+                    },
+                    _ => false
+                }
+            },
+            // UPower before 0.99 also signals changes on its own interface
+            (UPOWER, "DeviceAdded") | (UPOWER, "DeviceChanged") => match get_device_path(message) {
+                Some(device_path) => self.refresh(connection, device_path),
+                None => false
+            },
+            (UPOWER, "DeviceRemoved") => match get_device_path(message) {
+                Some(device_path) => self.devices.remove(&device_path).is_some(),
+                None => false
+            },
+            _ => false
+        }
+    }
+
+    /// Reads all properties of one device again.
+    fn refresh(&mut self, connection: &Connection, path: String) -> bool {
+        match get_properties(connection, &Path::from(path.clone())) {
+            Ok(properties) => {
+                if path == DISPLAY_DEVICE_PATH {
+                    self.display = properties;
+                } else {
+                    self.devices.insert(path, properties);
+                }
+                true
+            },
+            // Removed meanwhile
+            Err(_) => self.devices.remove(&path).is_some()
+        }
+    }
+
+    fn get_devices(&self) -> UPowerDevices {
+        let mut devices = UPowerDevices {
+            display: None,
+            batteries: Vec::new(),
+            peripherals: Vec::new()
+        };
+        if get_bool(&self.display, "IsPresent") {
+            devices.display = Some(to_battery(String::from("Total"), &self.display));
+        }
+        for properties in self.devices.values() {
+            let device_type = get_u64(properties, "Type");
+            if device_type == TYPE_LINE_POWER || !get_bool(properties, "IsPresent") {
+                continue;
+            }
+            if get_bool(properties, "PowerSupply") && device_type == TYPE_BATTERY {
+                let name = get_string(properties, "NativePath");
+                devices.batteries.push(to_battery(name, properties));
+            } else if !get_bool(properties, "PowerSupply") {
+                let name = match get_string(properties, "Model") {
+                    ref model if model.is_empty() => get_string(properties, "NativePath"),
+                    model => model
+                };
+                let mut battery = to_battery(name, properties);
+                battery.device_icon = device_icon(device_type);
+                devices.peripherals.push(battery);
+            }
+        }
+        devices.batteries.sort_by(|a, b| a.name.cmp(&b.name));
+        devices.peripherals.sort_by(|a, b| a.name.cmp(&b.name));
+        devices
+    }
+}
+
+/// Object path argument of the `DeviceAdded`, `DeviceRemoved` and
+/// `DeviceChanged` signals, a string before UPower 0.99.
+fn get_device_path(message: &Message) -> Option<String> {
+    message.get1::<Path>()
+        .map(|path| path.to_string())
+        .or_else(|| message.get1::<&str>().map(String::from))
+}
+
+fn get_properties(connection: &Connection, path: &Path) -> Result<DeviceProperties, dbus::Error> {
+    connection.with_path(UPOWER, path.clone(), TIMEOUT).get_all(DEVICE_INTERFACE)
+}
+
+fn get_u64(properties: &DeviceProperties, key: &str) -> u64 {
+    properties.get(key).and_then(|value| value.0.as_u64()).unwrap_or(0)
+}
+
+fn get_bool(properties: &DeviceProperties, key: &str) -> bool {
+    get_u64(properties, key) != 0
+}
+
+fn get_f64(properties: &DeviceProperties, key: &str) -> Option<f64> {
+    properties.get(key).and_then(|value| value.0.as_f64())
+}
+
+fn get_string(properties: &DeviceProperties, key: &str) -> String {
+    properties.get(key).and_then(|value| value.0.as_str()).unwrap_or("").to_string()
+}
+
+/// Energies are converted from Wh to the µWh sysfs uses, so both backends
+/// share the estimates.
+fn to_battery(name: String, properties: &DeviceProperties) -> Battery {
+    let state = match get_u64(properties, "State") {
+        1 => State::Charging,
+        2 | 3 | 6 => State::Discharging,
+        4 => State::Full,
+        5 => State::NotCharging,
+        _ => State::Unknown
+    };
+    let to_micro = |value: f64| (value * 1e6) as u64;
+    let time = match state {
+        State::Charging => properties.get("TimeToFull").and_then(|value| value.0.as_i64()),
+        _ => properties.get("TimeToEmpty").and_then(|value| value.0.as_i64())
+    };
+    let watts = get_f64(properties, "EnergyRate").map(f64::abs);
+    Battery {
+        name,
+        state,
+        charge: get_f64(properties, "Percentage").unwrap_or(0.0).round().min(100.0) as u8,
+        energy_now: get_f64(properties, "Energy").filter(|energy| *energy > 0.0).map(to_micro),
+        energy_full: get_f64(properties, "EnergyFull").filter(|energy| *energy > 0.0).map(to_micro),
+        rate: watts.filter(|watts| *watts > 0.0).map(to_micro),
+        watts,
+        estimate: time.filter(|time| *time > 0).map(|time| Duration::from_secs(time as u64)),
+        device_icon: None
+    }
+}
+
+fn device_icon(device_type: u64) -> Option<&'static str> {
+    match device_type {
+        5 => Some("\u{f245} "),
+        6 => Some("\u{f11c} "),
+        8 => Some("\u{f10b} "),
+        10 => Some("\u{f10a} "),
+        12 => Some("\u{f11b} "),
+        17 | 19 => Some("\u{f025} "),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::{Interface, Member, NameFlag};
+    use dbus::tree::Factory;
+    use std::cell::Cell;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    const BATTERY_PATH: &str = "/org/freedesktop/UPower/devices/battery_BAT0";
+    const TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A bus of its own, stopped when the test ends, passed or not.
+    struct DbusDaemon(Child);
+
+    impl Drop for DbusDaemon {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn open(address: &str) -> Result<Connection, dbus::Error> {
+        let connection = Connection::open_private(address)?;
+        connection.register()?;
+        Ok(connection)
+    }
+
+    /// Exports `org.freedesktop.UPower` with one discharging battery, which
+    /// then reports the percentages received on `rx`.
+    fn serve(address: &str, rx: Receiver<f64>, ready: Sender<()>, enumerations: Arc<AtomicUsize>)
+             -> Result<(), dbus::Error> {
+        let connection = open(address)?;
+        let factory = Factory::new_fn::<()>();
+        let percentage = Rc::new(Cell::new(60.0));
+        let percentage_clone = percentage.clone();
+        let tree = factory.tree(())
+            .add(factory.object_path(UPOWER_PATH, ()).introspectable().add(
+                factory.interface(UPOWER, ()).add_m(
+                    factory.method("EnumerateDevices", (), move |m| {
+                        enumerations.fetch_add(1, Ordering::SeqCst);
+                        Ok(vec![m.msg.method_return().append1(vec![Path::from(BATTERY_PATH)])])
+                    }).outarg::<Vec<Path>, _>("devices")
+                )
+            ))
+            .add(factory.object_path(DISPLAY_DEVICE_PATH, ()).introspectable().add(
+                factory.interface(DEVICE_INTERFACE, ())
+                    .add_p(factory.property::<bool, _>("IsPresent", ()).on_get(|i, _| { i.append(false); Ok(()) }))
+            ))
+            .add(factory.object_path(BATTERY_PATH, ()).introspectable().add(
+                factory.interface(DEVICE_INTERFACE, ())
+                    .add_p(factory.property::<&str, _>("NativePath", ()).on_get(|i, _| { i.append("BAT0"); Ok(()) }))
+                    .add_p(factory.property::<u32, _>("Type", ()).on_get(|i, _| { i.append(TYPE_BATTERY as u32); Ok(()) }))
+                    .add_p(factory.property::<bool, _>("PowerSupply", ()).on_get(|i, _| { i.append(true); Ok(()) }))
+                    .add_p(factory.property::<bool, _>("IsPresent", ()).on_get(|i, _| { i.append(true); Ok(()) }))
+                    .add_p(factory.property::<u32, _>("State", ()).on_get(|i, _| { i.append(2u32); Ok(()) }))
+                    .add_p(factory.property::<f64, _>("Percentage", ()).on_get(move |i, _| {
+                        i.append(percentage_clone.get());
+                        Ok(())
+                    }))
+                    .add_p(factory.property::<f64, _>("Energy", ()).on_get(|i, _| { i.append(30.0); Ok(()) }))
+                    .add_p(factory.property::<f64, _>("EnergyFull", ()).on_get(|i, _| { i.append(50.0); Ok(()) }))
+                    .add_p(factory.property::<f64, _>("EnergyRate", ()).on_get(|i, _| { i.append(10.0); Ok(()) }))
+                    .add_p(factory.property::<i64, _>("TimeToEmpty", ()).on_get(|i, _| { i.append(3 * 3600i64); Ok(()) }))
+            ));
+        connection.register_name(UPOWER, NameFlag::ReplaceExisting as u32)?;
+        tree.set_registered(&connection, true)?;
+        connection.add_handler(tree);
+        let _ = ready.send(());
+        loop {
+            connection.incoming(100).next();
+            if let Ok(value) = rx.try_recv() {
+                percentage.set(value);
+                let mut changed: DeviceProperties = HashMap::new();
+                changed.insert(String::from("Percentage"), Variant(Box::new(value) as Box<dyn RefArg>));
+                let signal = Message::signal(
+                    &Path::from(BATTERY_PATH),
+                    &Interface::from("org.freedesktop.DBus.Properties"),
+                    &Member::from("PropertiesChanged")
+                ).append3(DEVICE_INTERFACE, changed, Vec::<String>::new());
+                connection.send(signal)
+                    .map_err(|_| dbus::Error::new_custom("org.freedesktop.DBus.Error.Failed", "Cannot signal"))?;
+            }
+        }
+    }
+
+    /// Needs `dbus-daemon`, run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn fake_upower_on_a_private_session_bus() {
+        let mut daemon = DbusDaemon(Command::new("dbus-daemon")
+            .args(&["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("cannot run dbus-daemon"));
+        let mut address = String::new();
+        BufReader::new(daemon.0.stdout.take().unwrap()).read_line(&mut address).unwrap();
+        let address = address.trim().to_string();
+        let (percentage_tx, percentage_rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let enumerations = Arc::new(AtomicUsize::new(0));
+        let enumerations_clone = enumerations.clone();
+        let server_address = address.clone();
+        thread::spawn(move || serve(&server_address, percentage_rx, ready_tx, enumerations_clone).unwrap());
+        ready_rx.recv_timeout(TEST_TIMEOUT).unwrap();
+        let (tx, rx) = channel();
+        thread::spawn(move || watch_connection(&open(&address).unwrap(), 0, tx).unwrap());
+
+        let devices = rx.recv_timeout(TEST_TIMEOUT).unwrap();
+        assert!(devices.display.is_none());
+        assert!(devices.peripherals.is_empty());
+        assert_eq!(devices.batteries.len(), 1);
+        let battery = &devices.batteries[0];
+        assert_eq!(battery.name, "BAT0");
+        assert_eq!(battery.charge, 60);
+        assert_eq!(battery.state, State::Discharging);
+        assert_eq!(battery.energy_now, Some(30_000_000));
+        assert_eq!(battery.rate, Some(10_000_000));
+        assert_eq!(battery.estimate, Some(Duration::from_secs(3 * 3600)));
+
+        percentage_tx.send(55.0).unwrap();
+        let devices = rx.recv_timeout(TEST_TIMEOUT).unwrap();
+        assert_eq!(devices.batteries[0].charge, 55);
+        assert_eq!(devices.batteries[0].energy_now, Some(30_000_000));
+        // The signal carried the change, nothing was enumerated again
+        assert_eq!(enumerations.load(Ordering::SeqCst), 1);
+    }
+}
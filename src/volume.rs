@@ -0,0 +1,291 @@
+use gtk::{
+    ContainerExt,
+    EventBox,
+    Inhibit,
+    Label,
+    LabelExt,
+    WidgetExt
+};
+use gtk::Orientation::Horizontal;
+use alsa::{Mixer, PollDescriptors};
+use alsa::mixer::{SelemChannelId, SelemId};
+use glib_sys::{gboolean, gpointer, GIOCondition, G_IO_ERR, G_IO_HUP, G_IO_IN, GFALSE, GTRUE};
+use std::cell::RefCell;
+use std::panic;
+use std::os::raw::c_int;
+use crate::format::format;
+use crate::paint::set_label_color;
+use crate::pulse::PulseWidget;
+use crate::Settings;
+
+const DEFAULT_FORMAT: &str = "{icon}{percent}%";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum VolumeBackend {
+    /// A simple mixer control of an ALSA card.
+    Alsa,
+    /// The default sink of PulseAudio or pipewire-pulse, with sink
+    /// switching and a microphone indicator.
+    Pulse
+}
+
+#[derive(Clone)]
+pub struct VolumeSettings {
+    pub backend: VolumeBackend,
+    /// ALSA card, e.g. `default` or `hw:1`.
+    pub card: String,
+    /// Simple mixer control, e.g. `Master` or `PCM`.
+    pub control: String,
+    /// Percentage added or removed by each scroll step.
+    pub step: u32,
+    /// Placeholders are `{icon}` and `{percent}`.
+    pub format: String
+}
+
+impl Default for VolumeSettings {
+    fn default() -> VolumeSettings {
+        VolumeSettings {
+            backend: VolumeBackend::Alsa,
+            card: String::from("default"),
+            control: String::from("Master"),
+            step: 5,
+            format: String::from(DEFAULT_FORMAT)
+        }
+    }
+}
+
+pub struct VolumeWidget {
+    mixer: Mixer,
+    selem_id: SelemId,
+    label: Label,
+    format: String,
+    step: u32,
+    text: String,
+    muted: Option<bool>,
+    /// The card went away, e.g. an unplugged USB card.
+    closed: bool,
+    debug: bool
+}
+
+thread_local!(
+    static VOLUME_WIDGETS: RefCell<Vec<VolumeWidget>> = RefCell::new(Vec::new())
+);
+
+/// Called by GLib when ALSA has mixer events for the widget `user_data`.
+/// The source is removed once the mixer is closed, and a panic must not
+/// unwind into GLib.
+unsafe extern "C" fn on_mixer_event(_fd: c_int, condition: GIOCondition, user_data: gpointer) -> gboolean {
+    let id = user_data as usize;
+    match panic::catch_unwind(|| VolumeWidget::receive(id, condition)) {
+        Ok(true) => GTRUE,
+        _ => GFALSE
+    }
+}
+
+pub fn get_icon(percent: u32, muted: bool) -> &'static str {
+    match (percent, muted) {
+        (_, true) | (0, _) => "\u{f026} ",
+        (p, _) if p < 50 => "\u{f027} ",
+        _ => "\u{f028} "
+    }
+}
+
+impl VolumeWidget {
+    /// Volume in percent of the control range and whether it is muted,
+    /// from the first channel.
+    fn get_volume(&self) -> Option<(u32, bool)> {
+        if self.closed {
+            return None;
+        }
+        let selem = self.mixer.find_selem(&self.selem_id)?;
+        let (min, max) = selem.get_playback_volume_range();
+        let volume = selem.get_playback_volume(SelemChannelId::FrontLeft).ok()?;
+        let percent = if max > min {
+            ((volume - min) * 100 + (max - min) / 2) / (max - min)
+        } else {
+            0
+        };
+        let muted = selem.has_playback_switch()
+            && selem.get_playback_switch(SelemChannelId::FrontLeft).map(|switch| switch == 0).unwrap_or(false);
+        Some((percent as u32, muted))
+    }
+
+    fn update(&mut self, force_refresh: bool) {
+        let (text, muted) = match self.get_volume() {
+            Some((percent, muted)) => {
+                let text = format(&self.format, |key| match key {
+                    "icon" => Some(get_icon(percent, muted).to_string()),
+                    "percent" => Some(percent.to_string()),
+                    _ => None
+                });
+                (text, muted)
+            },
+            None => (String::from("\u{f026} ?"), true)
+        };
+        if self.text != text || force_refresh {
+            self.label.set_text(&text);
+            self.text = text;
+        }
+        if self.muted != Some(muted) || force_refresh {
+            if muted {
+                set_label_color(&self.label, 128, 128, 128);
+            } else {
+                set_label_color(&self.label, 255, 255, 255);
+            }
+            self.muted = Some(muted);
+        }
+    }
+
+    /// Whether to keep watching the mixer.
+    fn receive(id: usize, condition: GIOCondition) -> bool {
+        VOLUME_WIDGETS.with(|volume_widgets| {
+            let mut volume_widgets = volume_widgets.borrow_mut();
+            let volume_widget = match volume_widgets.get_mut(id) {
+                Some(volume_widget) => volume_widget,
+                None => return false
+            };
+            if condition & (G_IO_HUP | G_IO_ERR) != 0 {
+                if volume_widget.debug { println!("ALSA mixer closed"); }
+                volume_widget.closed = true;
+                volume_widget.update(false);
+                return false;
+            }
+            // Also makes the mixer reload the changed elements
+            match volume_widget.mixer.handle_events() {
+                Ok(_) => volume_widget.update(false),
+                Err(e) => if volume_widget.debug { println!("{}", e); }
+            }
+            true
+        })
+    }
+
+    /// Changes are not applied to the label here, ALSA sends an event back.
+    fn change_volume(id: usize, steps: i64) {
+        VOLUME_WIDGETS.with(|volume_widgets| {
+            if let Some(volume_widget) = volume_widgets.borrow().get(id) {
+                let selem = match volume_widget.mixer.find_selem(&volume_widget.selem_id) {
+                    Some(selem) => selem,
+                    None => return
+                };
+                let (min, max) = selem.get_playback_volume_range();
+                let (percent, _) = volume_widget.get_volume().unwrap_or((0, false));
+                let percent = (i64::from(percent) + steps * i64::from(volume_widget.step)).max(0).min(100);
+                // Rounded like get_volume, so a step reads back as the percent set
+                if let Err(e) = selem.set_playback_volume_all(min + ((max - min) * percent + 50) / 100) {
+                    if volume_widget.debug { println!("{}", e); }
+                }
+            }
+        });
+    }
+
+    fn toggle_mute(id: usize) {
+        VOLUME_WIDGETS.with(|volume_widgets| {
+            if let Some(volume_widget) = volume_widgets.borrow().get(id) {
+                if let (Some(selem), Some((_, muted))) = (volume_widget.mixer.find_selem(&volume_widget.selem_id),
+                                                         volume_widget.get_volume()) {
+                    if selem.has_playback_switch() {
+                        if let Err(e) = selem.set_playback_switch_all(if muted { 1 } else { 0 }) {
+                            if volume_widget.debug { println!("{}", e); }
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Hooks the mixer poll descriptors into the GLib main loop, so the
+    /// label only changes on ALSA events.
+    fn watch_mixer(mixer: &Mixer, id: usize) -> Result<(), alsa::Error> {
+        for poll_fd in PollDescriptors::get(mixer)? {
+            unsafe {
+                glib_sys::g_unix_fd_add_full(
+                    glib_sys::G_PRIORITY_DEFAULT,
+                    poll_fd.fd,
+                    G_IO_IN | G_IO_HUP | G_IO_ERR,
+                    Some(on_mixer_event),
+                    id as gpointer,
+                    None
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn new(settings: &Settings) -> gtk::Box {
+        match settings.volume.backend {
+            VolumeBackend::Alsa => {
+                let container = gtk::Box::new(Horizontal, 0);
+                container.add(&VolumeWidget::new_alsa(settings));
+                container
+            },
+            VolumeBackend::Pulse => PulseWidget::new(settings)
+        }
+    }
+
+    /// Scroll changes the volume, a click toggles mute. Hidden when the
+    /// mixer control cannot be opened.
+    fn new_alsa(settings: &Settings) -> EventBox {
+        let event_box = EventBox::new();
+        let label = Label::new(None);
+        label.set_margin_end(7);
+        event_box.add(&label);
+        let selem_id = SelemId::new(&settings.volume.control, 0);
+        let mixer = match Mixer::new(&settings.volume.card, false) {
+            Ok(ref mixer) if mixer.find_selem(&selem_id).is_none() => None,
+            Ok(mixer) => Some(mixer),
+            Err(e) => {
+                if settings.debug { println!("{}", e); }
+                None
+            }
+        };
+        let mixer = match mixer {
+            Some(mixer) => mixer,
+            None => {
+                event_box.set_no_show_all(true);
+                event_box.hide();
+                return event_box;
+            }
+        };
+        let id = VOLUME_WIDGETS.with(|volume_widgets| volume_widgets.borrow().len());
+        if let Err(e) = VolumeWidget::watch_mixer(&mixer, id) {
+            if settings.debug { println!("{}", e); }
+        }
+        let mut volume_widget = VolumeWidget {
+            mixer,
+            selem_id,
+            label,
+            format: settings.volume.format.clone(),
+            step: settings.volume.step,
+            text: String::new(),
+            muted: None,
+            closed: false,
+            debug: settings.debug
+        };
+        volume_widget.update(true);
+        VOLUME_WIDGETS.with(|volume_widgets| volume_widgets.borrow_mut().push(volume_widget));
+        event_box.add_events(gdk::EventMask::SCROLL_MASK.bits() as i32);
+        event_box.connect_scroll_event(move |_, event_scroll| {
+            let steps = match event_scroll.get_direction() {
+                gdk::ScrollDirection::Up => 1,
+                gdk::ScrollDirection::Down => -1,
+                gdk::ScrollDirection::Smooth => match event_scroll.get_delta().1 {
+                    delta if delta < 0.0 => 1,
+                    delta if delta > 0.0 => -1,
+                    _ => 0
+                },
+                _ => 0
+            };
+            if steps != 0 {
+                VolumeWidget::change_volume(id, steps);
+            }
+            Inhibit(false)
+        });
+        event_box.connect_button_press_event(move |_, event_button| {
+            if event_button.get_button() == 1 {
+                VolumeWidget::toggle_mute(id);
+            }
+            Inhibit(false)
+        });
+        event_box
+    }
+}
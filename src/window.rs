@@ -3,7 +3,14 @@ use gtk::Orientation::Horizontal;
 use std::rc::Rc;
 use crate::bspwm::BspwmDesktopsWidget;
 use crate::clock::ClockWidget;
+use crate::cpu::CpuWidget;
+use crate::disk::DiskWidget;
+use crate::memory::MemoryWidget;
+use crate::network::NetworkWidget;
 use crate::player::PlayerWidget;
+use crate::temperature::TemperatureWidget;
+use crate::timer::TimerWidget;
+use crate::volume::VolumeWidget;
 use crate::x11_title::X11TitleWidget;
 use crate::battery::BatteryWidget;
 use crate::Settings;
@@ -78,13 +85,27 @@ fn set_bar(window: &ApplicationWindow, screen_wrapper: ScreenWrapper, settings:
     let hbox = gtk::Box::new(Horizontal, 0);
     let desktops_labels = BspwmDesktopsWidget::new(&screen_wrapper.name);
     hbox.add(&desktops_labels);
-    let window_label = X11TitleWidget::new();
+    let window_label = X11TitleWidget::new(settings);
     hbox.add(&window_label);
     let artist_label = PlayerWidget::new(settings);
     hbox.add(&artist_label);
-    let battery_label = BatteryWidget::new();
+    let cpu_label = CpuWidget::new(settings);
+    hbox.add(&cpu_label);
+    let memory_label = MemoryWidget::new(settings);
+    hbox.add(&memory_label);
+    let disk_label = DiskWidget::new(settings);
+    hbox.add(&disk_label);
+    let temperature_label = TemperatureWidget::new(settings);
+    hbox.add(&temperature_label);
+    let network_label = NetworkWidget::new(settings);
+    hbox.add(&network_label);
+    let volume_label = VolumeWidget::new(settings);
+    hbox.add(&volume_label);
+    let battery_label = BatteryWidget::new(settings);
     hbox.add(&battery_label);
-    let time_label = ClockWidget::new();
+    let timer_label = TimerWidget::new(settings);
+    hbox.add(&timer_label);
+    let time_label = ClockWidget::new(settings);
     hbox.add(&time_label);
     window.add(&hbox);
     set_window_positions(&window, screen_wrapper.dimensions);
@@ -1,43 +1,43 @@
 use glib::Continue;
 use x11_get_windows::Session;
-use std::rc::Rc;
+use crate::marquee::Marquee;
 use crate::paint::set_label_color;
 use gtk::{
+    EventBox,
     Label,
-    LabelExt,
     timeout_add,
     WidgetExt
 };
-use crate::REFRESH_INTERVAL;
+use crate::{REFRESH_INTERVAL, Settings};
 
 pub struct X11TitleWidget {
     title: String
 }
 
 impl X11TitleWidget {
-    pub fn new() -> Label {
+    pub fn new(settings: &Settings) -> EventBox {
         let mut session = Session::open()
             .expect("Could not open a new session.");
         let label = Label::new(None);
         label.set_margin_end(7);
         set_label_color(&label, 255, 255, 255);
-        let label_rc = Rc::new(label.clone());
-        let label_clone = label_rc.clone();
+        let marquee = Marquee::new(&label, &settings.marquee);
+        let marquee_clone = marquee.clone();
         let mut x11_title_widget = X11TitleWidget {
             title: X11TitleWidget::get_current_title(&mut session)
         };
-        x11_title_widget.update(&mut session, &label_clone, true);
+        x11_title_widget.update(&mut session, &marquee_clone, true);
         timeout_add(REFRESH_INTERVAL, move || {
-            x11_title_widget.update(&mut session, &label_clone, false);
+            x11_title_widget.update(&mut session, &marquee_clone, false);
             Continue(true)
         });
-        label
+        marquee.widget().clone()
     }
 
-    fn update(&mut self, session: &mut Session, label: &Label, force_refresh: bool) {
+    fn update(&mut self, session: &mut Session, marquee: &Marquee, force_refresh: bool) {
         let title = X11TitleWidget::get_current_title(session);
         if self.title != title || force_refresh {
-            label.set_text(&title);
+            marquee.set_text(&title);
             self.title = title;
         }
     }